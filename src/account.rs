@@ -0,0 +1,157 @@
+use crate::logging::redact;
+use reqwest::Client;
+use serde::Deserialize;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// 配置文件中的一个元宝账号
+#[derive(Clone, Deserialize)]
+pub struct AccountConfig {
+    // 账号名称，用于日志和管理接口，不设置则按顺序编号
+    #[serde(default)]
+    pub name: Option<String>,
+    pub hy_user: String,
+    pub hy_token: String,
+    // 该账号每天最多的请求数，不设置则使用 daily_quota
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+}
+
+// 手动实现 Debug，隐藏账号凭证
+impl Debug for AccountConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountConfig")
+            .field("name", &self.name)
+            .field("hy_user", &redact(&self.hy_user))
+            .field("hy_token", &redact(&self.hy_token))
+            .field("daily_quota", &self.daily_quota)
+            .finish()
+    }
+}
+
+// 请求指定的账号不存在
+#[derive(Debug)]
+pub struct UnknownAccount(pub String);
+
+impl Display for UnknownAccount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "account '{}' does not exist", self.0)
+    }
+}
+
+impl std::error::Error for UnknownAccount {}
+
+// 只配置了单个账号时账号的名称
+const DEFAULT_ACCOUNT: &str = "default";
+
+// 一个账号及其客户端，Cookie 已经写在客户端的默认头部中
+pub struct Account {
+    pub name: String,
+    pub client: Client,
+    // 账号是否健康，凭证失效时置为 false
+    healthy: AtomicBool,
+    // 被限流或凭证失效后在这个时间之前不再使用
+    disabled_until: Mutex<Option<Instant>>,
+    // 被暂时跳过的时长
+    cooldown: Duration,
+}
+
+impl Account {
+    pub fn new(name: String, client: Client, cooldown: Duration) -> Account {
+        Account {
+            name,
+            client,
+            healthy: AtomicBool::new(true),
+            disabled_until: Mutex::new(None),
+            cooldown,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    // 上游接受了该账号的凭证
+    pub fn mark_healthy(&self) {
+        self.healthy.store(true, Ordering::Relaxed);
+        *self.disabled_until.lock().unwrap() = None;
+    }
+
+    // 上游拒绝了该账号的凭证，一段时间内跳过它
+    pub fn mark_failed(&self) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Account '{}' was rejected by upstream, skipping it for {}s",
+                self.name,
+                self.cooldown.as_secs()
+            );
+        }
+        self.cool_down();
+    }
+
+    // 上游限流了该账号，凭证仍然有效，同样一段时间内跳过它
+    pub fn mark_rate_limited(&self) {
+        warn!(
+            "Account '{}' was rate limited by upstream, skipping it for {}s",
+            self.name,
+            self.cooldown.as_secs()
+        );
+        self.cool_down();
+    }
+
+    fn cool_down(&self) {
+        *self.disabled_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+    }
+
+    fn is_available(&self) -> bool {
+        self.disabled_until
+            .lock()
+            .unwrap()
+            .is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+// 账号池，按轮询顺序分配账号
+pub struct AccountPool {
+    accounts: Vec<Arc<Account>>,
+    next: AtomicUsize,
+}
+
+impl AccountPool {
+    pub fn new(accounts: Vec<Arc<Account>>) -> AccountPool {
+        AccountPool {
+            accounts,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn accounts(&self) -> &[Arc<Account>] {
+        &self.accounts
+    }
+
+    // 从下一个账号开始轮询，返回第一个可用且 accept 为 true 的账号；
+    // 所有账号都被暂时跳过时不再跳过，以免全部不可用
+    pub fn pick(&self, mut accept: impl FnMut(&Account) -> bool) -> Option<Arc<Account>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let ordered = (0..self.accounts.len())
+            .map(|offset| &self.accounts[(start + offset) % self.accounts.len()]);
+        let all_disabled = !self.accounts.iter().any(|account| account.is_available());
+        ordered
+            .filter(|account| all_disabled || account.is_available())
+            .find(|account| accept(account))
+            .cloned()
+    }
+}
+
+// 账号的名称：优先使用配置的名称，只有一个账号时沿用默认名称
+pub fn account_name(config: &AccountConfig, index: usize, total: usize) -> String {
+    match &config.name {
+        Some(name) => name.clone(),
+        None if total == 1 => DEFAULT_ACCOUNT.to_string(),
+        None => format!("account-{}", index + 1),
+    }
+}
@@ -0,0 +1,196 @@
+use crate::metrics::METRICS;
+use async_channel::{Receiver, Sender, TrySendError, bounded};
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::warn;
+
+// 一条审计记录，只包含 prompt 的哈希，不包含原文
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    // 请求开始的 Unix 时间戳（毫秒）
+    pub timestamp: u64,
+    pub key: String,
+    pub model: String,
+    pub prompt_hash: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub finish_reason: String,
+    pub duration_ms: u64,
+    // 请求所用的账号，未能分配账号时为空
+    pub account: Option<String>,
+}
+
+// 等待写入的审计记录最多缓存的条数，写入跟不上时多出的直接丢弃并计数
+const MAX_QUEUED_RECORDS: usize = 1024;
+
+// 审计日志，记录通过后台任务异步追加写入
+pub struct AuditLog {
+    sender: Sender<AuditRecord>,
+}
+
+impl AuditLog {
+    // target 为 stdout 时写到标准输出，否则追加写入该路径的文件
+    pub fn new(target: &str) -> AuditLog {
+        let (sender, receiver) = bounded(MAX_QUEUED_RECORDS);
+        let target = target.to_string();
+        tokio::spawn(async move {
+            let result = if target == "stdout" {
+                Self::write_records(receiver, tokio::io::stdout()).await
+            } else {
+                match tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&target)
+                    .await
+                {
+                    Ok(file) => Self::write_records(receiver, file).await,
+                    Err(err) => Err(err),
+                }
+            };
+            if let Err(err) = result {
+                warn!("Audit log writer for {} stopped: {}", target, err);
+            }
+        });
+        AuditLog { sender }
+    }
+
+    // 每行一条 JSON 记录，队列中暂时没有记录时立即刷新到磁盘
+    async fn write_records(
+        receiver: Receiver<AuditRecord>,
+        writer: impl AsyncWrite + Unpin,
+    ) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        while let Ok(record) = receiver.recv().await {
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            if receiver.is_empty() {
+                writer.flush().await?;
+            }
+        }
+        writer.flush().await
+    }
+
+    // 队列已满时丢弃，不阻塞请求处理；只在第一次丢弃时输出警告，之后的数量见 /metrics
+    fn record(&self, record: AuditRecord) {
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if METRICS
+                    .audit_records_dropped
+                    .fetch_add(1, Ordering::Relaxed)
+                    == 0
+                {
+                    warn!("Audit log writer cannot keep up, dropping records");
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                METRICS
+                    .audit_records_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+                warn!("Audit log writer is gone, dropping a record");
+            }
+        }
+    }
+}
+
+// 单个请求的审计记录，请求结束（包括客户端中途断开）时写入且只写入一次
+pub struct AuditEntry {
+    log: Arc<AuditLog>,
+    record: Option<AuditRecord>,
+    started_at: Instant,
+}
+
+impl AuditEntry {
+    pub fn new(
+        log: Arc<AuditLog>,
+        key: &str,
+        model: &str,
+        prompt_hash: u64,
+        prompt_tokens: u64,
+    ) -> AuditEntry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        AuditEntry {
+            log,
+            record: Some(AuditRecord {
+                timestamp,
+                key: key.to_string(),
+                model: model.to_string(),
+                prompt_hash: format!("{:016x}", prompt_hash),
+                prompt_tokens,
+                completion_tokens: 0,
+                // 没有收到结束事件就被丢弃时，视为客户端取消
+                finish_reason: "cancelled".to_string(),
+                duration_ms: 0,
+                account: None,
+            }),
+            started_at: Instant::now(),
+        }
+    }
+
+    // 记录请求所用的账号
+    pub fn set_account(&mut self, account: &str) {
+        if let Some(record) = &mut self.record {
+            record.account = Some(account.to_string());
+        }
+    }
+
+    // 记录请求的结果
+    pub fn finish(&mut self, finish_reason: &str, completion_tokens: u64) {
+        if let Some(record) = &mut self.record {
+            record.finish_reason = finish_reason.to_string();
+            record.completion_tokens = completion_tokens;
+        }
+    }
+}
+
+impl Drop for AuditEntry {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.duration_ms = self.started_at.elapsed().as_millis() as u64;
+            self.log.record(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn each_request_writes_one_json_line() {
+        let path = std::env::temp_dir()
+            .join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        let log = Arc::new(AuditLog::new(&path));
+        let mut entry = AuditEntry::new(log, "team-a", "deepseek-v3", 0xabc, 12);
+        entry.set_account("default");
+        entry.finish("stop", 34);
+        drop(entry);
+        // 等待后台任务写入
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["key"], "team-a");
+        assert_eq!(record["model"], "deepseek-v3");
+        assert_eq!(record["prompt_hash"], "0000000000000abc");
+        assert_eq!(record["prompt_tokens"], 12);
+        assert_eq!(record["completion_tokens"], 34);
+        assert_eq!(record["finish_reason"], "stop");
+        assert_eq!(record["account"], "default");
+        assert!(record["timestamp"].as_u64().unwrap() > 0);
+        assert!(record["duration_ms"].is_u64());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::yuanbao::{ChatCompletionRequest, Completion, Yuanbao};
+use futures::future::BoxFuture;
+
+// 补全请求的后端，处理函数只通过它发起补全，可以替换为其他实现（如推送固定事件的桩）
+pub trait CompletionBackend: Send + Sync {
+    // 发起补全请求，返回的 Completion 的通道中依次收到回答的事件
+    fn create_completion(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> BoxFuture<'_, anyhow::Result<Completion>>;
+}
+
+impl CompletionBackend for Yuanbao {
+    fn create_completion(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> BoxFuture<'_, anyhow::Result<Completion>> {
+        Box::pin(Yuanbao::create_completion(self, request))
+    }
+}
+
+// 测试用的后端：按请求返回预先写好的事件，不请求上游
+#[cfg(test)]
+pub mod mock {
+    use super::CompletionBackend;
+    use crate::yuanbao::{
+        ChatCompletionEvent, ChatCompletionMessage, ChatCompletionMessageType,
+        ChatCompletionRequest, Completion,
+    };
+    use async_channel::bounded;
+    use futures::future::BoxFuture;
+    use std::sync::Mutex;
+    use tokio_util::sync::CancellationToken;
+
+    type Script = Box<
+        dyn Fn(&ChatCompletionRequest) -> anyhow::Result<Vec<ChatCompletionEvent>> + Send + Sync,
+    >;
+
+    // 每个请求调用一次 script 生成事件，全部放入通道后关闭；script 出错时补全请求失败
+    pub struct ScriptedBackend {
+        script: Script,
+        // 收到的请求，按到达的顺序
+        pub requests: Mutex<Vec<ChatCompletionRequest>>,
+    }
+
+    impl ScriptedBackend {
+        pub fn new(
+            script: impl Fn(&ChatCompletionRequest) -> anyhow::Result<Vec<ChatCompletionEvent>>
+            + Send
+            + Sync
+            + 'static,
+        ) -> ScriptedBackend {
+            ScriptedBackend {
+                script: Box::new(script),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        // 每个请求都返回相同的事件
+        pub fn events(
+            events: impl Fn() -> Vec<ChatCompletionEvent> + Send + Sync + 'static,
+        ) -> ScriptedBackend {
+            Self::new(move |_| Ok(events()))
+        }
+    }
+
+    impl CompletionBackend for ScriptedBackend {
+        fn create_completion(
+            &self,
+            request: ChatCompletionRequest,
+        ) -> BoxFuture<'_, anyhow::Result<Completion>> {
+            Box::pin(async move {
+                let events = (self.script)(&request)?;
+                self.requests.lock().unwrap().push(request);
+                let (sender, receiver) = bounded(events.len().max(1));
+                for event in events {
+                    sender.try_send(event).unwrap();
+                }
+                let cancel = CancellationToken::new();
+                Ok(Completion {
+                    account: "mock".to_string(),
+                    receiver,
+                    guard: cancel.clone().drop_guard(),
+                    cancel,
+                })
+            })
+        }
+    }
+
+    pub fn think(text: &str) -> ChatCompletionEvent {
+        ChatCompletionEvent::Message(ChatCompletionMessage {
+            r#type: ChatCompletionMessageType::Think,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn msg(text: &str) -> ChatCompletionEvent {
+        ChatCompletionEvent::Message(ChatCompletionMessage {
+            r#type: ChatCompletionMessageType::Msg,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn finish(reason: &str) -> ChatCompletionEvent {
+        ChatCompletionEvent::Finish(reason.to_string())
+    }
+}
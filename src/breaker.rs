@@ -0,0 +1,221 @@
+use crate::metrics::METRICS;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// 熔断器打开，暂时不请求上游
+#[derive(Debug)]
+pub struct CircuitOpen(pub Duration);
+
+impl Display for CircuitOpen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upstream is unavailable, retry in {} seconds",
+            self.0.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+// 熔断器配置
+#[derive(Clone, Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    // 时间窗口内连续失败多少次后打开
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: usize,
+    // 统计失败次数的时间窗口（秒）
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // 打开后多久放行一个试探请求（秒）
+    #[serde(default = "default_open_secs")]
+    pub open_secs: u64,
+}
+
+fn default_failure_threshold() -> usize {
+    5
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_open_secs() -> u64 {
+    30
+}
+
+// 熔断器的状态
+enum State {
+    // 正常请求，记录窗口内连续失败的时间
+    Closed(VecDeque<Instant>),
+    // 到指定时间之前直接拒绝
+    Open(Instant),
+    // 已经放行了一个试探请求，等待它的结果
+    HalfOpen(Instant),
+}
+
+// 上游连续失败时快速拒绝新请求，过一段时间后用一个请求试探上游是否恢复
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            state: Mutex::new(State::Closed(VecDeque::new())),
+        }
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_secs)
+    }
+
+    // 熔断器打开时返回错误；打开时间已过时放行一个试探请求
+    pub fn try_acquire(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed(_) => Ok(()),
+            State::Open(until) if now < until => Err(CircuitOpen(until - now)),
+            // 试探请求一直没有结果（如客户端断开）时，过了打开时间再放行一个
+            State::HalfOpen(started) if now < started + self.open_duration() => {
+                Err(CircuitOpen(started + self.open_duration() - now))
+            }
+            _ => {
+                info!("Circuit breaker is half-open, sending a probe request upstream");
+                *state = State::HalfOpen(now);
+                METRICS.circuit_breaker_state.store(2, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    // 记录一次上游请求的结果
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if success {
+            if !matches!(*state, State::Closed(_)) {
+                info!("Upstream recovered, closing the circuit breaker");
+            }
+            *state = State::Closed(VecDeque::new());
+            METRICS.circuit_breaker_state.store(0, Ordering::Relaxed);
+            return;
+        }
+        let open = match &mut *state {
+            State::Closed(failures) => {
+                let window = Duration::from_secs(self.config.window_secs);
+                failures.retain(|at| now.duration_since(*at) <= window);
+                failures.push_back(now);
+                failures.len() >= self.config.failure_threshold.max(1)
+            }
+            State::HalfOpen(_) => true,
+            State::Open(_) => false,
+        };
+        if open {
+            warn!(
+                "Upstream keeps failing, opening the circuit breaker for {} seconds",
+                self.config.open_secs
+            );
+            *state = State::Open(now + self.open_duration());
+            METRICS.circuit_breaker_state.store(1, Ordering::Relaxed);
+            METRICS
+                .circuit_breaker_opens
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // 当前状态的名称
+    pub fn state(&self) -> &'static str {
+        match *self.state.lock().unwrap() {
+            State::Closed(_) => "closed",
+            State::Open(until) if Instant::now() < until => "open",
+            State::Open(_) | State::HalfOpen(_) => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_breaker(window_secs: u64, open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            window_secs,
+            open_secs,
+        })
+    }
+
+    #[test]
+    fn opens_after_repeated_failures() {
+        let breaker = new_breaker(60, 60);
+        breaker.record(false);
+        assert_eq!(breaker.state(), "closed");
+        assert!(breaker.try_acquire().is_ok());
+        breaker.record(false);
+        assert_eq!(breaker.state(), "open");
+        let err = breaker.try_acquire().unwrap_err();
+        assert!(err.0 > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = new_breaker(60, 60);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let breaker = new_breaker(0, 60);
+        breaker.record(false);
+        std::thread::sleep(Duration::from_millis(5));
+        breaker.record(false);
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_reopens_the_breaker() {
+        let breaker = new_breaker(60, 0);
+        breaker.record(false);
+        breaker.record(false);
+        // 打开时间已过，放行一个试探请求
+        assert_eq!(breaker.state(), "half_open");
+        assert!(breaker.try_acquire().is_ok());
+        assert_eq!(breaker.state(), "half_open");
+        breaker.record(true);
+        assert_eq!(breaker.state(), "closed");
+
+        let breaker = new_breaker(60, 0);
+        breaker.record(false);
+        breaker.record(false);
+        assert!(breaker.try_acquire().is_ok());
+        // 试探失败时立即重新打开，不需要再累计失败次数
+        breaker.record(false);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open(_)));
+    }
+
+    #[test]
+    fn only_one_probe_is_sent_while_half_open() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            window_secs: 60,
+            open_secs: 60,
+        });
+        breaker.record(false);
+        // 模拟打开时间已过
+        *breaker.state.lock().unwrap() = State::Open(Instant::now());
+        assert!(breaker.try_acquire().is_ok());
+        assert!(breaker.try_acquire().is_err());
+    }
+}
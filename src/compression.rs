@@ -0,0 +1,320 @@
+// gzip 压缩；tower-http 和 flate2 无法使用，这里用固定 Huffman 编码的 deflate 自行实现，
+// 压缩率不如 zlib，但 JSON 响应的重复内容很多，仍然能明显减小体积
+
+// 小于这个字节数的响应不压缩
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+// LZ77 的窗口大小和最长匹配
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// 查找匹配时最多比较的候选位置数
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+// 长度码 257..285 的基础长度和额外位数
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// 距离码 0..29 的基础距离和额外位数
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// 按 deflate 的位序（低位在前）写入数据
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.buffer |= u64::from(value) << self.bits;
+        self.bits += count;
+        while self.bits >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    // Huffman 码从高位开始写
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+// 固定 Huffman 编码中字面量和长度码的码字
+fn write_symbol(writer: &mut BitWriter, symbol: u16) {
+    let symbol = u32::from(symbol);
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.partition_point(|&base| usize::from(base) <= length) - 1;
+    write_symbol(writer, 257 + code as u16);
+    writer.write_bits(
+        (length - usize::from(LENGTH_BASE[code])) as u32,
+        u32::from(LENGTH_EXTRA[code]),
+    );
+    let code = DISTANCE_BASE.partition_point(|&base| usize::from(base) <= distance) - 1;
+    writer.write_code(code as u32, 5);
+    writer.write_bits(
+        (distance - usize::from(DISTANCE_BASE[code])) as u32,
+        u32::from(DISTANCE_EXTRA[code]),
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// 用一个固定 Huffman 编码的块压缩全部数据
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2),
+        buffer: 0,
+        bits: 0,
+    };
+    // BFINAL=1，BTYPE=01（固定 Huffman）
+    writer.write_bits(0b011, 3);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |pos: usize, head: &mut [usize], prev: &mut [usize]| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(&data[pos..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, pos - candidate);
+                    if length == max {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // 链表中的位置只会越来越早，更早的位置已经被覆盖时停止
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        if best.0 >= MIN_MATCH {
+            write_match(&mut writer, best.0, best.1);
+            for offset in 0..best.0 {
+                insert(pos + offset, &mut head, &mut prev);
+            }
+            pos += best.0;
+        } else {
+            write_symbol(&mut writer, u16::from(data[pos]));
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    write_symbol(&mut writer, 256);
+    writer.finish()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// 压缩为 gzip 格式
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+// 客户端是否接受 gzip，q=0 表示不接受
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按位读取 deflate 数据，只需要解码固定 Huffman 块
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+            self.pos += 1;
+            u32::from(bit)
+        }
+
+        fn bits(&mut self, count: u8) -> usize {
+            (0..count).fold(0, |value, i| value | (self.bit() as usize) << i)
+        }
+
+        // Huffman 码从高位开始读
+        fn code(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        fn symbol(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.bit()) - 0x190,
+            }
+        }
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, pos: 0 };
+        assert_eq!(
+            reader.bits(3),
+            0b011,
+            "expected a single fixed Huffman block"
+        );
+        let mut out = Vec::new();
+        loop {
+            let symbol = reader.symbol() as usize;
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let code = symbol - 257;
+                    let length = usize::from(LENGTH_BASE[code]) + reader.bits(LENGTH_EXTRA[code]);
+                    let code = reader.code(5) as usize;
+                    let distance =
+                        usize::from(DISTANCE_BASE[code]) + reader.bits(DISTANCE_EXTRA[code]);
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!(data[..4], [0x1f, 0x8b, 8, 0]);
+        let (body, trailer) = data[10..].split_at(data.len() - 18);
+        let out = inflate(body);
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn gzip_of_empty_input_is_the_canonical_stream() {
+        assert_eq!(
+            gzip(b""),
+            [
+                0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let json = r#"{"id":"chatcmpl-1","object":"chat.completion","choices":[]}"#.repeat(200);
+        // 伪随机字节几乎没有重复，覆盖所有字面量码
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        // 超过最长匹配的连续字节，以及超出窗口大小的数据
+        let run = vec![b'a'; 1000];
+        let long: Vec<u8> = (0..WINDOW_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        for data in [json.as_bytes(), &noise, &run, &long, b"abcabcabc"] {
+            let compressed = gzip(data);
+            assert_eq!(gunzip(&compressed), data);
+        }
+        assert!(gzip(json.as_bytes()).len() < json.len() / 4);
+    }
+
+    #[test]
+    fn accepts_gzip_honors_q_values() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("gzip; q=0.0, br"));
+        assert!(!accepts_gzip("br, deflate"));
+        assert!(!accepts_gzip(""));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::logging::redact;
+use crate::yuanbao::estimate_tokens;
+use anyhow::{Context, Result, bail};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+
+// 向量接口的后端，元宝没有公开的向量接口，需要另外配置一个兼容 OpenAI 的服务
+#[derive(Clone, Deserialize)]
+pub struct EmbeddingsConfig {
+    // 后端 embeddings 接口的完整地址，如 https://api.openai.com/v1/embeddings
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    // 转发时使用的模型名，不设置则使用请求中的模型名
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+// 手动实现 Debug，隐藏后端的 API key
+impl Debug for EmbeddingsConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddingsConfig")
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_deref().map(redact))
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+// 向量接口的输入，可以是单个字符串或字符串数组
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(input) => vec![input],
+            EmbeddingInput::Many(inputs) => inputs,
+        }
+    }
+}
+
+// 定义向量请求的结构
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingsPayload {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub dimensions: Option<u32>,
+}
+
+// 请求向量后端的超时时间
+const EMBEDDINGS_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 向量后端的客户端
+pub struct Embeddings {
+    config: EmbeddingsConfig,
+    client: Client,
+}
+
+impl Embeddings {
+    pub fn new(config: EmbeddingsConfig) -> Embeddings {
+        let client = Client::builder()
+            .timeout(EMBEDDINGS_TIMEOUT)
+            .build()
+            .unwrap();
+        Embeddings { config, client }
+    }
+
+    // 转发给后端，返回 OpenAI 格式的响应，模型名回显请求中的
+    pub async fn create(
+        &self,
+        model: String,
+        inputs: Vec<String>,
+        dimensions: Option<u32>,
+    ) -> Result<Value> {
+        let mut body = json!({
+            "model": self.config.model.as_deref().unwrap_or(&model),
+            "input": inputs,
+            "encoding_format": "float",
+        });
+        if let Some(dimensions) = dimensions {
+            body["dimensions"] = json!(dimensions);
+        }
+        let mut request = self.client.post(&self.config.url).json(&body);
+        if let Some(api_key) = &self.config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request
+            .send()
+            .await
+            .context("cannot reach embeddings backend")?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            bail!("embeddings backend returned {}: {}", status, text);
+        }
+        let response: Value = response
+            .json()
+            .await
+            .context("cannot parse embeddings response")?;
+        let data: Vec<Value> = response["data"]
+            .as_array()
+            .context("embeddings response has no data")?
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                json!({
+                    "object": "embedding",
+                    "index": item["index"].as_u64().unwrap_or(index as u64),
+                    "embedding": item["embedding"],
+                })
+            })
+            .collect();
+        if data.len() != inputs.len() {
+            bail!(
+                "embeddings backend returned {} embeddings for {} inputs",
+                data.len(),
+                inputs.len()
+            );
+        }
+        // 后端没有返回用量时自行估算
+        let prompt_tokens = response["usage"]["prompt_tokens"]
+            .as_u64()
+            .unwrap_or_else(|| inputs.iter().map(|input| estimate_tokens(input)).sum());
+        Ok(json!({
+            "object": "list",
+            "data": data,
+            "model": model,
+            "usage": {
+                "prompt_tokens": prompt_tokens,
+                "total_tokens": prompt_tokens,
+            },
+        }))
+    }
+}
@@ -0,0 +1,107 @@
+use crate::logging::redact;
+use crate::ratelimit::{RateLimitConfig, RateLimited, RateLimiter};
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+// 配置文件中的一个 API key
+#[derive(Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    pub key: String,
+    // 用于日志和审计记录的名称，不设置则按顺序编号
+    #[serde(default)]
+    pub label: Option<String>,
+    // 该 key 的请求速率限制，global 和 per_account 都按该 key 计算，不设置则不限制
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // 允许使用的模型（请求中的模型名），为空时不限制
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+// 手动实现 Debug，隐藏 key
+impl Debug for ApiKeyConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKeyConfig")
+            .field("key", &redact(&self.key))
+            .field("label", &self.label)
+            .field("rate_limit", &self.rate_limit)
+            .field("models", &self.models)
+            .finish()
+    }
+}
+
+// 只配置了单个 key 时 key 的名称
+const DEFAULT_KEY_LABEL: &str = "default";
+
+// 一个 API key 及其设置，校验通过后放入请求的扩展中
+pub struct ApiKey {
+    pub label: String,
+    key: String,
+    models: Vec<String>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl ApiKey {
+    // 该 key 是否可以使用这个模型
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|allowed| allowed == model)
+    }
+
+    // 按该 key 的速率限制等待，超过限制时返回错误
+    pub async fn acquire(&self) -> Result<(), RateLimited> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.acquire(&self.label).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// 所有 API key；重新加载配置时重建，速率限制的计数也随之重置
+pub struct ApiKeys(Vec<Arc<ApiKey>>);
+
+impl ApiKeys {
+    pub fn new(configs: Vec<ApiKeyConfig>) -> ApiKeys {
+        let total = configs.len();
+        let keys = configs
+            .into_iter()
+            .enumerate()
+            .map(|(index, config)| {
+                let label = match config.label {
+                    Some(label) => label,
+                    None if total == 1 => DEFAULT_KEY_LABEL.to_string(),
+                    None => format!("key-{}", index + 1),
+                };
+                Arc::new(ApiKey {
+                    label,
+                    key: config.key,
+                    models: config.models,
+                    rate_limiter: config.rate_limit.map(RateLimiter::new),
+                })
+            })
+            .collect();
+        ApiKeys(keys)
+    }
+
+    // 查找与 token 匹配的 key，比较所有 key 且每次比较的耗时与内容无关
+    pub fn find(&self, token: &str) -> Option<Arc<ApiKey>> {
+        self.0
+            .iter()
+            .fold(None, |found, key| {
+                if constant_time_eq(token.as_bytes(), key.key.as_bytes()) {
+                    found.or(Some(key))
+                } else {
+                    found
+                }
+            })
+            .cloned()
+    }
+}
+
+// 比较两个字节串，耗时与内容无关，避免通过响应时间猜测 key
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,146 @@
+use crate::otlp::OtlpLayer;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::fmt::Debug;
+use std::io::Write;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber, info, warn};
+use tracing_subscriber::filter::{LevelFilter, filter_fn};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::layer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+// 日志的输出格式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // 便于阅读的文本
+    #[default]
+    Text,
+    // 每行一个 JSON 对象，便于日志系统采集
+    Json,
+}
+
+// 配置 tracing 日志，只输出本项目的日志；
+// 日志级别优先使用 RUST_LOG 环境变量（只支持单个级别，如 debug），其次是配置中的 level；
+// 设置了 otlp_endpoint 时同时把 span 导出到 OTLP 收集器，不受日志级别影响
+pub fn init(format: LogFormat, level: &str, otlp_endpoint: Option<&str>) {
+    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| level.to_string());
+    let filter = level.trim().parse::<LevelFilter>();
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match format {
+        LogFormat::Text => layer().boxed(),
+        LogFormat::Json => JsonLayer.boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(
+            layer
+                .with_filter(*filter.as_ref().unwrap_or(&LevelFilter::INFO))
+                .with_filter(filter_fn(is_own_target)),
+        )
+        .with(otlp_endpoint.map(|endpoint| {
+            OtlpLayer::new(endpoint)
+                .with_filter(filter_fn(|meta| meta.is_span() && is_own_target(meta)))
+        }))
+        .init();
+    if let Some(endpoint) = otlp_endpoint {
+        info!("Exporting traces to {}", endpoint);
+    }
+    if filter.is_err() {
+        warn!("Invalid log level '{}', using info", level);
+    }
+}
+
+fn is_own_target(meta: &Metadata<'_>) -> bool {
+    meta.target().starts_with("yuanbao_chat2api")
+}
+
+// 输出配置时代替凭证，只保留是否设置
+pub fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() { "" } else { "***" }
+}
+
+// 输出 JSON 日志的 layer；tracing-subscriber 的 json 特性需要额外的依赖，这里自行实现
+struct JsonLayer;
+
+// span 上记录的字段，保存在 span 的扩展中
+struct SpanFields(Map<String, Value>);
+
+// 把事件或 span 的字段写入 JSON 对象
+pub struct JsonVisitor<'a>(pub &'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+}
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(fields));
+        }
+    }
+
+    // 一行一个对象，包含时间、级别、来源、消息、所在 span 的字段和事件本身的字段
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        let meta = event.metadata();
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), json!(timestamp));
+        line.insert("level".to_string(), json!(meta.level().as_str()));
+        line.insert("target".to_string(), json!(meta.target()));
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    line.extend(fields.clone());
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+        let mut output = Value::Object(line).to_string();
+        output.push('\n');
+        let _ = std::io::stdout().lock().write_all(output.as_bytes());
+    }
+}
@@ -1,49 +1,204 @@
+mod account; // 引入 account.rs 模块
+mod audit; // 引入 audit.rs 模块
+mod backend; // 引入 backend.rs 模块
+mod breaker; // 引入 breaker.rs 模块
+mod compression; // 引入 compression.rs 模块
+mod embeddings; // 引入 embeddings.rs 模块
+mod keys; // 引入 keys.rs 模块
+mod logging; // 引入 logging.rs 模块
+mod metrics; // 引入 metrics.rs 模块
+mod otlp; // 引入 otlp.rs 模块
+mod quota; // 引入 quota.rs 模块
+mod ratelimit; // 引入 ratelimit.rs 模块
 mod service; // 引入 service.rs 模块
-use crate::service::{Config, Handler, Service};
+mod session; // 引入 session.rs 模块
+mod streams; // 引入 streams.rs 模块
+mod tls; // 引入 tls.rs 模块
+mod tools; // 引入 tools.rs 模块
+mod upload; // 引入 upload.rs 模块
+mod yuanbao; // 引入 yuanbao.rs 模块
+use crate::logging::LogFormat;
+use crate::service::{Config, Handler, Service, ServiceHandle};
+use crate::tls::TlsListener;
 use anyhow::Context;
 use axum::Router;
-use axum::extract::State;
+use axum::extract::DefaultBodyLimit;
+use axum::middleware::{from_fn, from_fn_with_state};
 use axum::routing::{get, post};
+use futures::future::BoxFuture;
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::{info, instrument};
-use tracing_subscriber::{filter::LevelFilter, fmt::layer, util::SubscriberInitExt};
-use tracing_subscriber::filter::filter_fn;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{debug, info, instrument, warn};
 
 #[instrument]
 #[tokio::main]
 async fn main() {
-    // 配置 tracing 日志
-    tracing_subscriber::registry()
-        .with(
-            layer()
-                .with_filter(LevelFilter::INFO) // 使用 LevelFilter::INFO
-                .with_filter(filter_fn(|meta| {
-                    meta.target().starts_with("yuanbao_chat2api")
-                }))
-        )
-        .init();
+    // 读取配置文件和环境变量，日志格式由配置决定，因此先读取配置
+    let config = Config::load("config.yml").context("cannot load configuration");
+    match &config {
+        Ok(config) => logging::init(
+            config.log_format,
+            &config.log_level,
+            config.otlp_endpoint.as_deref(),
+        ),
+        Err(_) => logging::init(LogFormat::default(), "info", None),
+    }
+    let config = config.unwrap();
+    // Debug 输出中凭证已隐藏
+    debug!("Loaded configuration: {:?}", config);
+    config.log_warnings();
 
-    // 读取配置文件
-    let config: Config = tokio::fs::read_to_string("config.yml")
-        .await
-        .context("cannot get config.yaml")
-        .unwrap()
-        .parse()
-        .context("cannot parse config.yaml")
-        .unwrap();
-    
+    let host = config.host.clone();
     let port = config.port;
+    let unix_socket = config.unix_socket.clone();
+    let max_body_bytes = config.max_body_bytes;
+    let metrics_public = config.metrics_public;
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    // 证书有问题时直接退出，不回退到 HTTP
+    let tls = config.tls.as_ref().map(|tls| {
+        tls::load_acceptor(tls)
+            .context("cannot load TLS certificate")
+            .unwrap()
+    });
     let service = Service::new(config);
-    let app = Router::new()
+    service.start_readiness_checks();
+    let service = ServiceHandle::new(service);
+    service.start_config_reload("config.yml");
+    // 管理接口使用单独的 admin_key，客户端的 key 不能访问
+    let admin = Router::new()
+        .route("/admin/accounts", get(Handler::admin_accounts))
+        .route_layer(from_fn_with_state(
+            service.clone(),
+            Handler::authorize_admin,
+        ));
+    // 除健康检查外的接口都需要校验 API key；设置了 metrics_public 时 /metrics 也不需要
+    let mut api = Router::new()
         .route("/v1/models", get(Handler::models))
+        .route("/v1/models/{id}", get(Handler::model))
         .route("/v1/chat/completions", post(Handler::chat_completions))
+        .route(
+            "/v1/chat/completions/{id}/cancel",
+            post(Handler::cancel_completion),
+        )
+        .route("/v1/completions", post(Handler::completions))
+        .route("/v1/compare", post(Handler::compare))
+        .route("/v1/embeddings", post(Handler::embeddings))
+        .route("/v1/debug/echo", post(Handler::debug_echo));
+    if !metrics_public {
+        api = api.route("/metrics", get(Handler::metrics));
+    }
+    let mut app = api
+        .route_layer(from_fn_with_state(service.clone(), Handler::authorize))
+        .merge(admin);
+    if metrics_public {
+        app = app.route("/metrics", get(Handler::metrics));
+    }
+    let app = app
+        .route("/health", get(Handler::health))
+        .route("/health/live", get(Handler::liveness))
+        .route("/health/ready", get(Handler::readiness))
+        // 对所有接口生效，补全、embeddings 等接口的请求体不能超过 max_body_bytes
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(from_fn_with_state(service.clone(), Handler::cors))
+        .layer(from_fn_with_state(service.clone(), Handler::compress))
+        .layer(from_fn(Handler::request_id))
         .with_state(service);
 
-    // 绑定端口并启动服务器
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .unwrap();
-    
-    info!("Launched the service on :{port}");
-    axum::serve(listener, app).await.unwrap();
+    // 收到退出信号后不再接受新连接，等待进行中的请求完成，超时后直接退出
+    let shutdown = Arc::new(Notify::new());
+    let graceful = {
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down, waiting up to {shutdown_timeout:?} for in-flight requests");
+            shutdown.notify_one();
+        }
+    };
+    // 绑定 Unix 套接字或 TCP 地址和端口并启动服务器
+    let server: BoxFuture<std::io::Result<()>> = if let Some(path) = unix_socket {
+        let listener = bind_unix_socket(&path)
+            .with_context(|| format!("cannot listen on {path}"))
+            .unwrap();
+        info!("Launched the service on unix:{path}");
+        Box::pin(
+            axum::serve(listener, app)
+                .with_graceful_shutdown(graceful)
+                .into_future(),
+        )
+    } else {
+        let listener = TcpListener::bind((host.as_str(), port))
+            .await
+            .with_context(|| format!("cannot listen on {host}:{port}"))
+            .unwrap();
+        match tls {
+            Some(acceptor) => {
+                let listener = TlsListener::new(listener, acceptor).unwrap();
+                info!("Launched the service on https://{host}:{port}");
+                Box::pin(
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(graceful)
+                        .into_future(),
+                )
+            }
+            None => {
+                info!("Launched the service on {host}:{port}");
+                Box::pin(
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(graceful)
+                        .into_future(),
+                )
+            }
+        }
+    };
+    select! {
+        result = server => result.unwrap(),
+        _ = async {
+            shutdown.notified().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => warn!("In-flight requests did not finish within {shutdown_timeout:?}, exiting"),
+    }
+}
+
+// 绑定 Unix 套接字，上次运行留下的套接字文件先删除
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{path} exists and is not a socket");
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+#[cfg(not(unix))]
+fn bind_unix_socket(_path: &str) -> anyhow::Result<TcpListener> {
+    anyhow::bail!("unix_socket is only supported on Unix")
+}
+
+// 等待 Ctrl+C 或 SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
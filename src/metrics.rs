@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// 运行指标，各模块直接更新其中的计数器
+#[derive(Default)]
+pub struct Metrics {
+    // 因上游 HTTP/2 GOAWAY 触发的重连次数
+    pub goaway_reconnects: AtomicU64,
+    // 向上游发起的补全请求数，可以用来计算当前的请求速率
+    pub upstream_requests: AtomicU64,
+    // 因上游速率限制被拒绝的请求数
+    pub upstream_rate_limited: AtomicU64,
+    // 检测到的客户端重连风暴次数
+    pub reconnect_storms: AtomicU64,
+    // 上游 SSE 流出错的次数
+    pub upstream_errors: AtomicU64,
+    // 发送给客户端的 token 数估算（正文和推理内容）
+    pub tokens_emitted: AtomicU64,
+    // 熔断器打开的次数
+    pub circuit_breaker_opens: AtomicU64,
+    // 熔断器的当前状态：0 关闭，1 打开，2 半开
+    pub circuit_breaker_state: AtomicU64,
+    // 写入跟不上而丢弃的审计记录数
+    pub audit_records_dropped: AtomicU64,
+    // 各模型收到的聊天补全请求数
+    chat_requests: Mutex<BTreeMap<String, u64>>,
+    // 各账号上游请求成功和失败的次数，按账号名称记录，重新加载配置后也保留
+    account_results: Mutex<BTreeMap<String, (u64, u64)>>,
+    // 最近 RATE_WINDOW 内向上游发起请求的时间，用来计算当前的请求速率
+    upstream_request_times: Mutex<VecDeque<Instant>>,
+    // 上游 SSE 流的持续时间
+    pub stream_duration: Histogram,
+}
+
+// 计算当前上游请求速率的时间窗口
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+impl Metrics {
+    // 记录一次聊天补全请求
+    pub fn record_chat_request(&self, model: &str) {
+        *self
+            .chat_requests
+            .lock()
+            .unwrap()
+            .entry(model.to_string())
+            .or_default() += 1;
+    }
+
+    // 记录一次向上游发起的补全请求
+    pub fn record_upstream_request(&self) {
+        self.upstream_requests.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut times = self.upstream_request_times.lock().unwrap();
+        Self::prune(&mut times, now);
+        times.push_back(now);
+    }
+
+    // 最近 RATE_WINDOW 内平均每秒向上游发起的请求数
+    pub fn upstream_request_rate(&self) -> f64 {
+        let mut times = self.upstream_request_times.lock().unwrap();
+        Self::prune(&mut times, Instant::now());
+        times.len() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn prune(times: &mut VecDeque<Instant>, now: Instant) {
+        while times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= RATE_WINDOW)
+        {
+            times.pop_front();
+        }
+    }
+
+    // 记录账号的一次上游请求的结果
+    pub fn record_account_result(&self, account: &str, success: bool) {
+        let mut results = self.account_results.lock().unwrap();
+        let (successes, failures) = results.entry(account.to_string()).or_default();
+        if success {
+            *successes += 1;
+        } else {
+            *failures += 1;
+        }
+    }
+
+    // 以 Prometheus 文本格式输出全部指标
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "goaway_reconnects_total",
+                "Reconnects caused by upstream HTTP/2 GOAWAY",
+                &self.goaway_reconnects,
+            ),
+            (
+                "upstream_requests_total",
+                "Completion requests sent upstream",
+                &self.upstream_requests,
+            ),
+            (
+                "upstream_rate_limited_total",
+                "Requests rejected by the upstream rate limit",
+                &self.upstream_rate_limited,
+            ),
+            (
+                "reconnect_storms_total",
+                "Repeated requests rejected as reconnect storms",
+                &self.reconnect_storms,
+            ),
+            (
+                "upstream_errors_total",
+                "Upstream SSE streams that ended with an error",
+                &self.upstream_errors,
+            ),
+            (
+                "tokens_emitted_total",
+                "Estimated tokens sent to clients",
+                &self.tokens_emitted,
+            ),
+            (
+                "circuit_breaker_opens_total",
+                "Times the upstream circuit breaker opened",
+                &self.circuit_breaker_opens,
+            ),
+            (
+                "audit_records_dropped_total",
+                "Audit records dropped because the writer could not keep up",
+                &self.audit_records_dropped,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {}{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}{} counter", PREFIX, name);
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                PREFIX,
+                name,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let name = format!("{}circuit_breaker_state", PREFIX);
+        let _ = writeln!(
+            out,
+            "# HELP {} Upstream circuit breaker state (0 closed, 1 open, 2 half-open)",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            name,
+            self.circuit_breaker_state.load(Ordering::Relaxed)
+        );
+
+        let name = format!("{}upstream_requests_per_second", PREFIX);
+        let _ = writeln!(
+            out,
+            "# HELP {} Completion requests sent upstream per second over the last {}s",
+            name,
+            RATE_WINDOW.as_secs()
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, self.upstream_request_rate());
+
+        let name = format!("{}chat_completion_requests_total", PREFIX);
+        let _ = writeln!(out, "# HELP {} Chat completion requests by model", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (model, count) in self.chat_requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{model=\"{}\"}} {}",
+                name,
+                escape_label(model),
+                count
+            );
+        }
+
+        let name = format!("{}account_requests_total", PREFIX);
+        let _ = writeln!(
+            out,
+            "# HELP {} Upstream requests by account and result",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (account, (successes, failures)) in self.account_results.lock().unwrap().iter() {
+            for (result, count) in [("success", successes), ("failure", failures)] {
+                let _ = writeln!(
+                    out,
+                    "{}{{account=\"{}\",result=\"{}\"}} {}",
+                    name,
+                    escape_label(account),
+                    result,
+                    count
+                );
+            }
+        }
+
+        self.stream_duration.render(
+            &mut out,
+            &format!("{}stream_duration_seconds", PREFIX),
+            "Duration of upstream SSE streams",
+        );
+        out
+    }
+}
+
+// 指标名称的前缀
+const PREFIX: &str = "yuanbao_";
+
+// 转义标签值中的特殊字符
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// 直方图的分桶上限（秒）
+const DURATION_BUCKETS: [f64; 9] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+// 耗时直方图
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    // 总耗时（微秒）
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            if seconds <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                le,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_request_rate_is_a_gauge() {
+        let metrics = Metrics::default();
+        for _ in 0..5 {
+            metrics.record_upstream_request();
+        }
+        assert_eq!(metrics.upstream_request_rate(), 0.5);
+        let out = metrics.render();
+        assert!(out.contains("yuanbao_upstream_requests_total 5\n"), "{out}");
+        assert!(out.contains("# TYPE yuanbao_upstream_requests_per_second gauge\n"));
+        assert!(
+            out.contains("yuanbao_upstream_requests_per_second 0.5\n"),
+            "{out}"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::logging::JsonVisitor;
+use async_channel::{Receiver, Sender, bounded};
+use reqwest::Client;
+use serde_json::{Map, Value, json};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Subscriber, warn};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use uuid::Uuid;
+
+// 把 span 导出到 OTLP 收集器；opentelemetry 系列的库无法使用，这里用 OTLP/HTTP 的 JSON 格式自行实现
+
+// 资源和 scope 的名称
+const SERVICE_NAME: &str = "yuanbao-chat2api";
+
+// 待导出的 span 最多缓存的个数，收集器不可用时多出的直接丢弃
+const MAX_QUEUED_SPANS: usize = 2048;
+
+// 每次请求最多导出的 span 个数
+const MAX_BATCH_SPANS: usize = 512;
+
+// 收到第一个 span 后等待这么久再导出，把同一段时间的 span 合并为一次请求
+const EXPORT_DELAY: Duration = Duration::from_secs(5);
+
+// 请求 span 上记录客户端传入的 traceparent 的字段名
+pub const TRACEPARENT_FIELD: &str = "traceparent";
+
+// span 的 kind：SPAN_KIND_INTERNAL 和 SPAN_KIND_SERVER
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+// 正在进行中的 span，保存在 span 的扩展中
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    attributes: Map<String, Value>,
+}
+
+// 导出 span 的 layer，span 关闭时放入队列，由后台任务批量发送
+pub struct OtlpLayer {
+    sender: Sender<Value>,
+}
+
+impl OtlpLayer {
+    // endpoint 为收集器的地址，如 http://localhost:4318，span 发送到其中的 /v1/traces
+    pub fn new(endpoint: &str) -> OtlpLayer {
+        let (sender, receiver) = bounded(MAX_QUEUED_SPANS);
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        tokio::spawn(export(url, receiver));
+        OtlpLayer { sender }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OtlpSpan>()
+                .map(|parent| (parent.trace_id.clone(), parent.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_hex(16), None),
+        };
+        let mut attributes = Map::new();
+        attrs.record(&mut JsonVisitor(&mut attributes));
+        let mut otlp_span = OtlpSpan {
+            trace_id,
+            span_id: random_hex(8),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        };
+        apply_traceparent(&mut otlp_span);
+        span.extensions_mut().insert(otlp_span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(otlp_span) = span.extensions_mut().get_mut::<OtlpSpan>() {
+            values.record(&mut JsonVisitor(&mut otlp_span.attributes));
+            apply_traceparent(otlp_span);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(otlp_span) = span.extensions_mut().remove::<OtlpSpan>() else {
+            return;
+        };
+        let kind = if span.parent().is_none() {
+            KIND_SERVER
+        } else {
+            KIND_INTERNAL
+        };
+        let attributes: Vec<Value> = otlp_span
+            .attributes
+            .into_iter()
+            .map(|(key, value)| json!({"key": key, "value": any_value(value)}))
+            .collect();
+        let mut value = json!({
+            "traceId": otlp_span.trace_id,
+            "spanId": otlp_span.span_id,
+            "name": span.name(),
+            "kind": kind,
+            "startTimeUnixNano": unix_nanos(otlp_span.start).to_string(),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()).to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent_span_id) = otlp_span.parent_span_id {
+            value["parentSpanId"] = json!(parent_span_id);
+        }
+        // 队列已满时丢弃，不阻塞请求处理
+        let _ = self.sender.try_send(value);
+    }
+}
+
+// 根 span 上记录了合法的 traceparent 时，沿用其中的 trace ID 并以调用方的 span 作为父 span
+fn apply_traceparent(span: &mut OtlpSpan) {
+    let Some(traceparent) = span.attributes.remove(TRACEPARENT_FIELD) else {
+        return;
+    };
+    if span.parent_span_id.is_some() {
+        return;
+    }
+    if let Some((trace_id, parent_span_id)) = traceparent.as_str().and_then(parse_traceparent) {
+        span.trace_id = trace_id;
+        span.parent_span_id = Some(parent_span_id);
+    }
+}
+
+// 解析 W3C traceparent（版本-trace ID-父 span ID-标志），返回 trace ID 和父 span ID
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let is_hex = |part: &str, len: usize| {
+        part.len() == len
+            && part
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let all_zero = |part: &str| part.bytes().all(|byte| byte == b'0');
+    (is_hex(version, 2)
+        && version != "ff"
+        && is_hex(trace_id, 32)
+        && !all_zero(trace_id)
+        && is_hex(parent_id, 16)
+        && !all_zero(parent_id)
+        && is_hex(flags, 2))
+    .then(|| (trace_id.to_string(), parent_id.to_string()))
+}
+
+// 指定字节数的随机 ID，以十六进制表示
+fn random_hex(bytes: usize) -> String {
+    Uuid::new_v4().as_bytes()[..bytes]
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// 把字段的值转换为 OTLP 的 AnyValue
+fn any_value(value: Value) -> Value {
+    match value {
+        Value::Bool(value) => json!({"boolValue": value}),
+        Value::Number(number) if number.is_f64() => json!({"doubleValue": number}),
+        Value::Number(number) => json!({"intValue": number.to_string()}),
+        Value::String(value) => json!({"stringValue": value}),
+        value => json!({"stringValue": value.to_string()}),
+    }
+}
+
+// 后台批量导出 span；收集器不可用时只在第一次失败时输出警告
+async fn export(url: String, receiver: Receiver<Value>) {
+    let client = Client::new();
+    let mut failing = false;
+    while let Ok(first) = receiver.recv().await {
+        sleep(EXPORT_DELAY).await;
+        let mut spans = vec![first];
+        while spans.len() < MAX_BATCH_SPANS
+            && let Ok(span) = receiver.try_recv()
+        {
+            spans.push(span);
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        {"key": "service.name", "value": {"stringValue": SERVICE_NAME}},
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }],
+            }],
+        });
+        let result = client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => failing = false,
+            Err(err) if !failing => {
+                failing = true;
+                warn!("Cannot export spans to {}: {}", url, err);
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+// 账号当日配额已用完
+#[derive(Debug)]
+pub struct QuotaExceeded(pub String);
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "daily quota of account '{}' is exhausted", self.0)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+// 配额计数，可以持久化到状态文件
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct QuotaState {
+    // 计数所属的日期（UTC 时间的第几天）
+    day: u64,
+    // 各账号当日已用的请求数
+    used: HashMap<String, u64>,
+}
+
+// 某个账号的配额使用情况
+#[derive(Debug, Serialize)]
+pub struct QuotaUsage {
+    pub used: u64,
+    pub quota: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
+// 状态文件的写入：后台任务依次写入，每次只写最新的内容，较早的内容直接跳过
+#[derive(Default)]
+struct StateWriter {
+    // 还没有写入的最新内容
+    pending: Mutex<Option<String>>,
+    // 同一时间只有一个任务在写文件
+    write_lock: Mutex<()>,
+}
+
+// 按账号统计每日请求数，达到配额后拒绝，次日自动清零
+pub struct QuotaTracker {
+    daily_quota: Option<u64>,
+    // 单独设置了配额的账号，优先于 daily_quota
+    account_quotas: HashMap<String, u64>,
+    state_file: Option<String>,
+    state: Mutex<QuotaState>,
+    writer: Arc<StateWriter>,
+}
+
+impl QuotaTracker {
+    pub fn new(
+        daily_quota: Option<u64>,
+        account_quotas: HashMap<String, u64>,
+        state_file: Option<String>,
+    ) -> QuotaTracker {
+        let state = state_file.as_deref().map(Self::load).unwrap_or_default();
+        QuotaTracker {
+            daily_quota,
+            account_quotas,
+            state_file,
+            state: Mutex::new(state),
+            writer: Arc::default(),
+        }
+    }
+
+    // 读取状态文件；文件不存在时从零开始，无法解析时记录警告并把原文件改名为 .corrupt 保留
+    fn load(path: &str) -> QuotaState {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return QuotaState::default(),
+            Err(err) => {
+                warn!("Cannot read quota state from {}: {}", path, err);
+                return QuotaState::default();
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(state) => state,
+            Err(err) => {
+                let corrupt = format!("{}.corrupt", path);
+                warn!(
+                    "Cannot parse quota state in {}, counting from zero and keeping it as {}: {}",
+                    path, corrupt, err
+                );
+                if let Err(err) = std::fs::rename(path, &corrupt) {
+                    warn!("Cannot rename {} to {}: {}", path, corrupt, err);
+                }
+                QuotaState::default()
+            }
+        }
+    }
+
+    // 账号每天的配额，没有单独设置时使用 daily_quota
+    fn quota(&self, account: &str) -> Option<u64> {
+        self.account_quotas
+            .get(account)
+            .copied()
+            .or(self.daily_quota)
+    }
+
+    // 账号当日的配额是否还有剩余，只检查不计数
+    pub fn has_remaining(&self, account: &str) -> bool {
+        let Some(quota) = self.quota(account) else {
+            return true;
+        };
+        let mut state = self.state.lock().unwrap();
+        Self::reset_if_new_day(&mut state);
+        state.used.get(account).copied().unwrap_or(0) < quota
+    }
+
+    // 尝试为账号记一次请求，配额不足时返回 false
+    pub fn try_acquire(&self, account: &str) -> bool {
+        let quota = self.quota(account);
+        let mut state = self.state.lock().unwrap();
+        Self::reset_if_new_day(&mut state);
+        let used = state.used.entry(account.to_string()).or_default();
+        if quota.is_some_and(|quota| *used >= quota) {
+            return false;
+        }
+        *used += 1;
+        if Some(*used) == quota {
+            warn!("Account '{}' reached its daily quota of {}", account, used);
+        }
+        self.persist(&state);
+        true
+    }
+
+    // 获取账号当日的配额使用情况
+    pub fn usage(&self, account: &str) -> QuotaUsage {
+        let quota = self.quota(account);
+        let mut state = self.state.lock().unwrap();
+        Self::reset_if_new_day(&mut state);
+        let used = state.used.get(account).copied().unwrap_or(0);
+        QuotaUsage {
+            used,
+            quota,
+            remaining: quota.map(|quota| quota.saturating_sub(used)),
+        }
+    }
+
+    fn reset_if_new_day(state: &mut QuotaState) {
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400;
+        if state.day != today {
+            if !state.used.is_empty() {
+                info!("Daily quota counters reset");
+            }
+            state.day = today;
+            state.used.clear();
+        }
+    }
+
+    // 将计数写入状态文件：先写临时文件再改名，中途退出也不会留下不完整的文件；失败时只记录日志
+    fn persist(&self, state: &QuotaState) {
+        let Some(path) = self.state_file.clone() else {
+            return;
+        };
+        let content = match serde_json::to_string(state) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("Cannot serialize quota state: {}", err);
+                return;
+            }
+        };
+        *self.writer.pending.lock().unwrap() = Some(content);
+        let writer = self.writer.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = writer.write_lock.lock().unwrap();
+            // 已经被之前的任务写入时跳过
+            let Some(content) = writer.pending.lock().unwrap().take() else {
+                return;
+            };
+            let temp = format!("{}.tmp", path);
+            if let Err(err) =
+                std::fs::write(&temp, content).and_then(|_| std::fs::rename(&temp, &path))
+            {
+                warn!("Cannot write quota state to {}: {}", path, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn state_file() -> String {
+        std::env::temp_dir()
+            .join(format!("quota-{}.json", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn persisted_counts_survive_a_restart() {
+        let path = state_file();
+        let tracker = QuotaTracker::new(Some(3), HashMap::new(), Some(path.clone()));
+        for _ in 0..3 {
+            assert!(tracker.try_acquire("a"));
+        }
+        assert!(!tracker.has_remaining("a"));
+        assert!(!tracker.try_acquire("a"));
+        // 等待后台写入完成
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let restarted = QuotaTracker::new(Some(3), HashMap::new(), Some(path.clone()));
+        assert_eq!(restarted.usage("a").used, 3);
+        assert!(restarted.has_remaining("b"));
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn account_quotas_override_the_daily_quota() {
+        let tracker = QuotaTracker::new(Some(1), HashMap::from([("big".to_string(), 3)]), None);
+        for _ in 0..3 {
+            assert!(tracker.try_acquire("big"));
+        }
+        assert!(!tracker.try_acquire("big"));
+        assert!(tracker.try_acquire("small"));
+        assert!(!tracker.has_remaining("small"));
+        let usage = tracker.usage("big");
+        assert_eq!(
+            (usage.used, usage.quota, usage.remaining),
+            (3, Some(3), Some(0))
+        );
+        // 没有 daily_quota 时只限制单独设置了配额的账号
+        let tracker = QuotaTracker::new(None, HashMap::from([("big".to_string(), 1)]), None);
+        assert!(tracker.try_acquire("big"));
+        assert!(!tracker.has_remaining("big"));
+        assert!(tracker.try_acquire("other"));
+        assert_eq!(tracker.usage("other").quota, None);
+    }
+
+    #[test]
+    fn corrupt_state_file_is_kept_aside() {
+        let path = state_file();
+        std::fs::write(&path, "{\"day\": 1, \"used\": ").unwrap();
+        let tracker = QuotaTracker::new(Some(3), HashMap::new(), Some(path.clone()));
+        assert_eq!(tracker.usage("a").used, 0);
+        let corrupt = format!("{}.corrupt", path);
+        assert!(std::path::Path::new(&corrupt).exists());
+        assert!(!std::path::Path::new(&path).exists());
+        let _ = std::fs::remove_file(&corrupt);
+    }
+}
@@ -0,0 +1,353 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// 请求上游的速率超过限制
+#[derive(Debug)]
+pub struct RateLimited;
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream rate limit reached, try again later")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+// 超过速率限制时的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitPolicy {
+    // 排队等待，最多等待 max_wait_ms
+    #[default]
+    Queue,
+    // 直接返回 429
+    Reject,
+}
+
+// 请求上游的速率限制配置
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    // 全局每秒最多的请求数
+    #[serde(default)]
+    pub global: Option<f64>,
+    // 每个账号每秒最多的请求数
+    #[serde(default)]
+    pub per_account: Option<f64>,
+    // 允许的突发请求数
+    #[serde(default = "default_burst")]
+    pub burst: f64,
+    #[serde(default)]
+    pub policy: RateLimitPolicy,
+    // 排队时最长的等待时间（毫秒）
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl RateLimitConfig {
+    // 检查速率和突发数，0、负数和 NaN 会导致令牌桶计算等待时间时出错
+    pub fn validate(&self, name: &str) -> anyhow::Result<()> {
+        let rates = [("global", self.global), ("per_account", self.per_account)];
+        for (field, rate) in rates {
+            if let Some(rate) = rate
+                && !(rate.is_finite() && rate > 0.0)
+            {
+                anyhow::bail!("{}.{} must be a positive number", name, field);
+            }
+        }
+        if !(self.burst.is_finite() && self.burst > 0.0) {
+            anyhow::bail!("{}.burst must be a positive number", name);
+        }
+        Ok(())
+    }
+}
+
+fn default_burst() -> f64 {
+    1.0
+}
+
+fn default_max_wait_ms() -> u64 {
+    2000
+}
+
+// 令牌桶
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    // 当前令牌数和上次补充的时间
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> TokenBucket {
+        // 桶容量小于 1 时永远取不到令牌
+        let burst = burst.max(1.0);
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    // 尝试取出一个令牌，不足时返回需要等待的时间
+    fn try_take(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let tokens =
+            (state.0 + now.duration_since(state.1).as_secs_f64() * self.rate).min(self.burst);
+        *state = (tokens, now);
+        if tokens >= 1.0 {
+            state.0 -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+        }
+    }
+
+    // 归还一个令牌
+    fn refund(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = (state.0 + 1.0).min(self.burst);
+    }
+}
+
+// 全局和按账号的上游速率限制
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: Option<TokenBucket>,
+    accounts: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let global = config
+            .global
+            .map(|rate| TokenBucket::new(rate, config.burst));
+        RateLimiter {
+            config,
+            global,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 等待可以向上游发起请求，超过限制且无法在等待时间内获得令牌时返回错误
+    pub async fn acquire(&self, account: &str) -> Result<(), RateLimited> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.max_wait_ms);
+        loop {
+            let wait = match self.try_acquire(account) {
+                None => return Ok(()),
+                Some(wait) => wait,
+            };
+            if self.config.policy == RateLimitPolicy::Reject || Instant::now() + wait > deadline {
+                return Err(RateLimited);
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn try_acquire(&self, account: &str) -> Option<Duration> {
+        let account_bucket = self.config.per_account.map(|rate| {
+            self.accounts
+                .lock()
+                .unwrap()
+                .entry(account.to_string())
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate, self.config.burst)))
+                .clone()
+        });
+        if let Some(global) = &self.global
+            && let Some(wait) = global.try_take()
+        {
+            return Some(wait);
+        }
+        if let Some(bucket) = account_bucket
+            && let Some(wait) = bucket.try_take()
+        {
+            // 账号令牌不足时归还已经取出的全局令牌
+            if let Some(global) = &self.global {
+                global.refund();
+            }
+            return Some(wait);
+        }
+        None
+    }
+}
+
+// 短时间内重复发起相同请求的检测配置
+#[derive(Clone, Debug, Deserialize)]
+pub struct StormConfig {
+    // 统计窗口（秒）
+    #[serde(default = "default_storm_window_secs")]
+    pub window_secs: u64,
+    // 窗口内相同请求超过这个次数即视为重连风暴
+    #[serde(default = "default_storm_threshold")]
+    pub threshold: usize,
+}
+
+fn default_storm_window_secs() -> u64 {
+    10
+}
+
+fn default_storm_threshold() -> usize {
+    3
+}
+
+// 检测客户端异常重连导致的重复请求
+pub struct StormDetector {
+    window: Duration,
+    threshold: usize,
+    // 请求指纹对应的最近请求时间
+    requests: Mutex<HashMap<u64, VecDeque<Instant>>>,
+}
+
+// 指纹数量超过这个值时清理过期的记录
+const STORM_CLEANUP_THRESHOLD: usize = 1024;
+
+impl StormDetector {
+    pub fn new(config: &StormConfig) -> StormDetector {
+        StormDetector {
+            window: Duration::from_secs(config.window_secs),
+            threshold: config.threshold.max(1),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 记录一次请求，窗口内相同的请求过多时返回 true
+    pub fn is_storm(&self, fingerprint: u64) -> bool {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() > STORM_CLEANUP_THRESHOLD {
+            requests.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|time| now.duration_since(*time) < self.window)
+            });
+        }
+        let times = requests.entry(fingerprint).or_default();
+        while times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= self.window)
+        {
+            times.pop_front();
+        }
+        times.push_back(now);
+        times.len() > self.threshold
+    }
+}
+
+// 进行中的补全数达到上限
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many concurrent requests, try again later")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+// 同时进行中的补全数限制，每个选项都占用一个上游连接
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConcurrencyConfig {
+    // 最多同时进行的补全数
+    pub max: u32,
+    #[serde(default)]
+    pub policy: RateLimitPolicy,
+    // 排队时最长的等待时间（毫秒）
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+// 用信号量限制进行中的补全数，许可在响应结束时释放
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> ConcurrencyLimiter {
+        let max = config.max.max(1);
+        ConcurrencyLimiter {
+            config: ConcurrencyConfig { max, ..config },
+            semaphore: Arc::new(Semaphore::new(max as usize)),
+        }
+    }
+
+    // 为 count 个补全获取许可，超过上限的按上限计算，避免永远拿不到
+    pub async fn acquire(&self, count: u32) -> Result<OwnedSemaphorePermit, Overloaded> {
+        let count = count.clamp(1, self.config.max);
+        if self.config.policy == RateLimitPolicy::Reject {
+            return self
+                .semaphore
+                .clone()
+                .try_acquire_many_owned(count)
+                .map_err(|_| Overloaded);
+        }
+        let wait = Duration::from_millis(self.config.max_wait_ms);
+        match tokio::time::timeout(wait, self.semaphore.clone().acquire_many_owned(count)).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(Overloaded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(yaml: &str) -> RateLimiter {
+        RateLimiter::new(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[tokio::test]
+    async fn reject_policy_fails_immediately() {
+        let limiter = limiter("global: 0.5\npolicy: reject");
+        assert!(limiter.acquire("a").await.is_ok());
+        let started = Instant::now();
+        assert!(limiter.acquire("a").await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn queue_policy_waits_for_a_token() {
+        let limiter = limiter("global: 20\nmax_wait_ms: 500");
+        assert!(limiter.acquire("a").await.is_ok());
+        let started = Instant::now();
+        assert!(limiter.acquire("a").await.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        // 等待时间超过 max_wait_ms 时直接失败
+        let limiter = self::limiter("global: 0.5\nmax_wait_ms: 10");
+        assert!(limiter.acquire("a").await.is_ok());
+        assert!(limiter.acquire("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn accounts_have_separate_buckets() {
+        // 全局令牌每 10 毫秒补充一个，账号令牌 2 秒补充一个
+        let limiter = limiter("global: 100\nper_account: 0.5\npolicy: reject");
+        assert!(limiter.acquire("a").await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(limiter.acquire("a").await.is_err());
+        // a 被拒绝时归还了全局令牌，b 不用等待全局令牌补充
+        assert!(limiter.acquire("b").await.is_ok());
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for yaml in [
+            "global: 0",
+            "global: -1",
+            "per_account: .nan",
+            "global: .inf",
+            "burst: 0",
+        ] {
+            let config: RateLimitConfig = serde_yaml::from_str(yaml).unwrap();
+            assert!(config.validate("upstream_rate_limit").is_err(), "{yaml}");
+        }
+        let config: RateLimitConfig = serde_yaml::from_str("global: 0.5\nper_account: 2").unwrap();
+        assert!(config.validate("upstream_rate_limit").is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// 会话映射：把客户端的会话标识对应到元宝的对话，后续请求接着同一个对话
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionConfig {
+    // 最多保存的会话数，超出时淘汰最久未使用的
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    // 携带会话标识的请求头，没有时使用请求体中的 user 字段
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+fn default_capacity() -> usize {
+    1000
+}
+
+fn default_header() -> String {
+    "x-session-id".to_string()
+}
+
+// 一个会话对应的元宝对话，对话属于创建它的账号
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub account: String,
+    pub agent_id: String,
+    pub conversation_id: String,
+}
+
+// 按最近使用时间淘汰的会话表
+pub struct SessionStore {
+    capacity: usize,
+    inner: Mutex<SessionTable>,
+}
+
+#[derive(Default)]
+struct SessionTable {
+    // 会话及其最近一次使用的序号
+    entries: HashMap<String, (Session, u64)>,
+    tick: u64,
+}
+
+impl SessionStore {
+    pub fn new(config: &SessionConfig) -> SessionStore {
+        SessionStore {
+            capacity: config.capacity.max(1),
+            inner: Mutex::new(SessionTable::default()),
+        }
+    }
+
+    // 查找会话并刷新它的使用时间
+    pub fn get(&self, key: &str) -> Option<Session> {
+        let mut table = self.inner.lock().unwrap();
+        table.tick += 1;
+        let tick = table.tick;
+        let (session, last_used) = table.entries.get_mut(key)?;
+        *last_used = tick;
+        Some(session.clone())
+    }
+
+    // 保存会话，超出容量时淘汰最久未使用的一个
+    pub fn insert(&self, key: String, session: Session) {
+        let mut table = self.inner.lock().unwrap();
+        table.tick += 1;
+        let tick = table.tick;
+        table.entries.insert(key, (session, tick));
+        if table.entries.len() > self.capacity {
+            // 容量不大，线性查找最旧的即可
+            let oldest = table
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                table.entries.remove(&oldest);
+            }
+        }
+    }
+
+    // 会话对应的对话不可用时删除它
+    pub fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().entries.remove(key);
+    }
+}
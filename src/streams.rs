@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+// 进行中的流式响应，按响应 ID 记录所属的 API key 和各选项的取消令牌
+#[derive(Default)]
+pub struct ActiveStreams {
+    streams: Mutex<HashMap<String, (String, Vec<CancellationToken>)>>,
+}
+
+impl ActiveStreams {
+    // 记录一个流式响应，返回的守卫丢弃时（流结束或客户端断开）移除记录
+    pub fn register(
+        self: &Arc<Self>,
+        id: &str,
+        key_label: &str,
+        tokens: Vec<CancellationToken>,
+    ) -> ActiveStream {
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), (key_label.to_string(), tokens));
+        ActiveStream {
+            streams: self.clone(),
+            id: id.to_string(),
+        }
+    }
+
+    // 取消一个流式响应，只能取消同一个 API key 发起的，找不到时返回 false
+    pub fn cancel(&self, id: &str, key_label: &str) -> bool {
+        let streams = self.streams.lock().unwrap();
+        match streams.get(id) {
+            Some((label, tokens)) if label == key_label => {
+                tokens.iter().for_each(CancellationToken::cancel);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// 流式响应的记录，丢弃时移除
+pub struct ActiveStream {
+    streams: Arc<ActiveStreams>,
+    id: String,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.streams.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_owning_key_can_cancel() {
+        let streams = Arc::new(ActiveStreams::default());
+        let tokens = vec![CancellationToken::new(), CancellationToken::new()];
+        let _active = streams.register("chatcmpl-1", "alice", tokens.clone());
+        assert!(!streams.cancel("chatcmpl-1", "bob"));
+        assert!(!streams.cancel("chatcmpl-2", "alice"));
+        assert!(tokens.iter().all(|token| !token.is_cancelled()));
+        assert!(streams.cancel("chatcmpl-1", "alice"));
+        assert!(tokens.iter().all(CancellationToken::is_cancelled));
+    }
+
+    #[test]
+    fn dropping_the_guard_unregisters_the_stream() {
+        let streams = Arc::new(ActiveStreams::default());
+        let token = CancellationToken::new();
+        drop(streams.register("chatcmpl-1", "alice", vec![token.clone()]));
+        assert!(!streams.cancel("chatcmpl-1", "alice"));
+        assert!(!token.is_cancelled());
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use axum::serve::Listener;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, warn};
+
+// 直接提供 HTTPS 时使用的证书，均为 PEM 格式的文件路径
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    // 证书链，服务器证书在前
+    pub cert: String,
+    // 私钥，支持 PKCS#8、PKCS#1 和 SEC1
+    pub key: String,
+}
+
+// TLS 握手的超时时间，避免不完成握手的连接一直占用资源
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 读取证书和私钥
+pub fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let cert_file =
+        File::open(&config.cert).with_context(|| format!("cannot open {}", config.cert))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("cannot read certificates from {}", config.cert))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", config.cert);
+    }
+    let key_file =
+        File::open(&config.key).with_context(|| format!("cannot open {}", config.key))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .with_context(|| format!("cannot read private key from {}", config.key))?
+        .with_context(|| format!("no private key found in {}", config.key))?;
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("certificate and private key do not match")?;
+    // axum 只启用了 HTTP/1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// 接受 TLS 连接的监听器；握手在单独的任务中进行，慢的客户端不会阻塞其他连接
+pub struct TlsListener {
+    receiver: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Cannot accept a connection: {}", err);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(err)) => debug!("TLS handshake with {} failed: {}", addr, err),
+                        Err(_) => debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            receiver,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.receiver.recv().await {
+            Some(accepted) => accepted,
+            // 接受连接的任务不会主动退出
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
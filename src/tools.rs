@@ -0,0 +1,283 @@
+use crate::yuanbao::{ChatMessage, ChatMessages};
+use serde_json::{Value, json};
+
+// 模型输出工具调用时使用的标签
+const TOOL_CALL_OPEN: &str = "<tool_call>";
+const TOOL_CALL_CLOSE: &str = "</tool_call>";
+
+// 请求中的工具定义，兼容 tools 和旧版的 functions，统一为 {name, description, parameters}
+pub fn tool_definitions(tools: &[Value], functions: &[Value]) -> Vec<Value> {
+    tools
+        .iter()
+        .filter(|tool| tool["type"].as_str().unwrap_or("function") == "function")
+        .map(|tool| tool.get("function").unwrap_or(tool))
+        .chain(functions)
+        .filter(|function| function["name"].is_string())
+        .map(|function| {
+            json!({
+                "name": function["name"],
+                "description": function["description"],
+                "parameters": function["parameters"],
+            })
+        })
+        .collect()
+}
+
+// 按 tool_choice 生成工具说明，tool_choice 为 none 或没有工具时返回 None
+fn tool_prompt(definitions: &[Value], tool_choice: Option<&Value>) -> Option<String> {
+    if definitions.is_empty() {
+        return None;
+    }
+    let requirement = match tool_choice {
+        Some(Value::String(choice)) if choice == "none" => return None,
+        Some(Value::String(choice)) if choice == "required" => {
+            "这次回答必须调用至少一个工具。".to_string()
+        }
+        Some(choice) if choice["function"]["name"].is_string() => {
+            format!(
+                "这次回答必须调用工具 {}。",
+                choice["function"]["name"].as_str().unwrap_or_default()
+            )
+        }
+        _ => "不需要调用工具时直接回答。".to_string(),
+    };
+    let definitions: Vec<String> = definitions.iter().map(Value::to_string).collect();
+    Some(format!(
+        "你可以调用下面这些工具：\n<tools>\n{}\n</tools>\n需要调用工具时，只输出一个或多个如下格式的工具调用，不要输出其他内容：\n{}{{\"name\": \"工具名\", \"arguments\": {{参数}}}}{}\n{}",
+        definitions.join("\n"),
+        TOOL_CALL_OPEN,
+        TOOL_CALL_CLOSE,
+        requirement
+    ))
+}
+
+// 把工具说明作为 system 消息放在最前面，并把历史中的工具调用写成文本；
+// 返回是否需要从回答中解析工具调用
+pub fn inject_tools(
+    messages: &mut ChatMessages,
+    definitions: &[Value],
+    tool_choice: Option<&Value>,
+) -> bool {
+    for item in &mut messages.0 {
+        let Some(calls) = item.tool_calls.take() else {
+            continue;
+        };
+        let calls: Vec<String> = calls.iter().map(render_tool_call).collect();
+        let content = item.content.as_deref().unwrap_or("").trim();
+        item.content = Some(if content.is_empty() {
+            calls.join("\n")
+        } else {
+            format!("{}\n{}", content, calls.join("\n"))
+        });
+    }
+    let Some(prompt) = tool_prompt(definitions, tool_choice) else {
+        return false;
+    };
+    messages.0.insert(
+        0,
+        ChatMessage {
+            role: "system".to_string(),
+            content: Some(prompt),
+            reasoning_content: None,
+            tool_calls: None,
+            images: Vec::new(),
+        },
+    );
+    true
+}
+
+// 把请求历史中的一个 OpenAI 工具调用写成模型输出的格式
+fn render_tool_call(call: &Value) -> String {
+    let function = &call["function"];
+    // arguments 是 JSON 字符串，能解析时展开，避免二次转义
+    let arguments = match &function["arguments"] {
+        Value::String(arguments) => serde_json::from_str(arguments).unwrap_or(json!(arguments)),
+        arguments => arguments.clone(),
+    };
+    format!(
+        "{}{}{}",
+        TOOL_CALL_OPEN,
+        json!({"name": function["name"], "arguments": arguments}),
+        TOOL_CALL_CLOSE
+    )
+}
+
+// 从回答中取出所有工具调用，返回剩下的正文和 OpenAI 格式的 tool_calls；
+// 无法解析的调用保留在正文中
+pub fn parse_tool_calls(text: &str) -> (String, Vec<Value>) {
+    let mut rest = String::new();
+    let mut calls = Vec::new();
+    let mut remaining = text;
+    while let Some(start) = remaining.find(TOOL_CALL_OPEN) {
+        rest.push_str(&remaining[..start]);
+        let body = &remaining[start + TOOL_CALL_OPEN.len()..];
+        // 回答被截断时可能没有结束标签
+        let (inner, next) = match body.find(TOOL_CALL_CLOSE) {
+            Some(end) => (&body[..end], &body[end + TOOL_CALL_CLOSE.len()..]),
+            None => (body, ""),
+        };
+        match make_tool_call(inner.trim()) {
+            Some(call) => calls.push(call),
+            None => rest.push_str(&remaining[start..remaining.len() - next.len()]),
+        }
+        remaining = next;
+    }
+    rest.push_str(remaining);
+    (rest.trim().to_string(), calls)
+}
+
+// 将模型输出的 {"name", "arguments"} 转为 OpenAI 的 tool_calls 元素
+fn make_tool_call(text: &str) -> Option<Value> {
+    let call: Value = serde_json::from_str(text).ok()?;
+    let name = call["name"].as_str()?;
+    let arguments = match &call["arguments"] {
+        Value::Null => "{}".to_string(),
+        Value::String(arguments) => arguments.clone(),
+        arguments => arguments.to_string(),
+    };
+    Some(json!({
+        "id": format!("call_{}", uuid::Uuid::new_v4().simple()),
+        "type": "function",
+        "function": {"name": name, "arguments": arguments},
+    }))
+}
+
+// 流式响应中识别工具调用：工具调用开始之前的正文照常输出，之后的内容留到结束时解析
+#[derive(Default)]
+pub struct ToolCallParser {
+    // 可能是标签开头、暂时不能输出的正文
+    pending: String,
+    // 从第一个工具调用标签开始的内容
+    captured: Option<String>,
+}
+
+impl ToolCallParser {
+    // 追加一段正文，返回现在可以输出的部分
+    pub fn push(&mut self, text: &str) -> String {
+        if let Some(captured) = &mut self.captured {
+            captured.push_str(text);
+            return String::new();
+        }
+        self.pending.push_str(text);
+        if let Some(start) = self.pending.find(TOOL_CALL_OPEN) {
+            self.captured = Some(self.pending.split_off(start));
+            return std::mem::take(&mut self.pending);
+        }
+        let keep = (1..TOOL_CALL_OPEN.len())
+            .rev()
+            .find(|&len| self.pending.ends_with(&TOOL_CALL_OPEN[..len]))
+            .unwrap_or(0);
+        let emit = self.pending.len() - keep;
+        self.pending.drain(..emit).collect()
+    }
+
+    // 回答结束，返回还没有输出的正文和解析出的工具调用
+    pub fn finish(self) -> (String, Vec<Value>) {
+        let mut text = self.pending;
+        if let Some(captured) = self.captured {
+            text.push_str(&captured);
+        }
+        if text.contains(TOOL_CALL_OPEN) {
+            parse_tool_calls(&text)
+        } else {
+            (text, Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            reasoning_content: None,
+            tool_calls: None,
+            images: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_tool_calls_extracts_calls_and_keeps_the_rest() {
+        let (rest, calls) = parse_tool_calls(
+            "查一下 <tool_call>{\"name\": \"weather\", \"arguments\": {\"city\": \"北京\"}}</tool_call> 好的",
+        );
+        assert_eq!(rest, "查一下  好的");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0]["type"], "function");
+        assert_eq!(calls[0]["function"]["name"], "weather");
+        assert_eq!(calls[0]["function"]["arguments"], "{\"city\":\"北京\"}");
+        assert!(calls[0]["id"].as_str().unwrap().starts_with("call_"));
+    }
+
+    #[test]
+    fn parse_tool_calls_handles_truncated_and_invalid_calls() {
+        // 被截断、没有结束标签的调用仍然可以解析
+        let (rest, calls) = parse_tool_calls("<tool_call>{\"name\": \"now\"}");
+        assert_eq!(rest, "");
+        assert_eq!(calls[0]["function"]["arguments"], "{}");
+        // 无法解析的调用原样留在正文中
+        let text = "<tool_call>{\"name\": </tool_call>";
+        assert_eq!(parse_tool_calls(text), (text.to_string(), Vec::new()));
+        let (rest, calls) = parse_tool_calls(
+            "<tool_call>{\"arguments\": {}}</tool_call><tool_call>{\"name\": \"a\"}</tool_call>",
+        );
+        assert_eq!(rest, "<tool_call>{\"arguments\": {}}</tool_call>");
+        assert_eq!(calls.len(), 1);
+    }
+
+    #[test]
+    fn parser_holds_back_a_tag_split_across_chunks() {
+        let mut parser = ToolCallParser::default();
+        assert_eq!(parser.push("好的<tool"), "好的");
+        assert_eq!(parser.push("_call>{\"name\": "), "");
+        assert_eq!(parser.push("\"a\"}</tool_call>"), "");
+        let (text, calls) = parser.finish();
+        assert_eq!(text, "");
+        assert_eq!(calls[0]["function"]["name"], "a");
+    }
+
+    #[test]
+    fn parser_releases_text_that_is_not_a_tag() {
+        let mut parser = ToolCallParser::default();
+        assert_eq!(parser.push("a <to"), "a ");
+        assert_eq!(parser.push("p>"), "<top>");
+        assert_eq!(parser.push("结尾<"), "结尾");
+        assert_eq!(parser.finish(), ("<".to_string(), Vec::new()));
+    }
+
+    #[test]
+    fn inject_tools_adds_the_prompt_and_renders_history() {
+        let tools =
+            [json!({"type": "function", "function": {"name": "weather", "parameters": {}}})];
+        let definitions = tool_definitions(&tools, &[json!({"name": "legacy"})]);
+        assert_eq!(definitions.len(), 2);
+        let mut call = message("assistant", "");
+        call.tool_calls = Some(vec![json!({
+            "function": {"name": "weather", "arguments": "{\"city\":\"北京\"}"}
+        })]);
+        let mut messages = ChatMessages(vec![message("user", "天气"), call]);
+        assert!(inject_tools(&mut messages, &definitions, None));
+        assert_eq!(messages.0[0].role, "system");
+        assert!(
+            messages.0[0]
+                .content
+                .as_deref()
+                .unwrap()
+                .contains("\"legacy\"")
+        );
+        assert_eq!(
+            messages.0[2].content.as_deref(),
+            Some("<tool_call>{\"arguments\":{\"city\":\"北京\"},\"name\":\"weather\"}</tool_call>")
+        );
+        let mut messages = ChatMessages(vec![message("user", "天气")]);
+        assert!(!inject_tools(
+            &mut messages,
+            &definitions,
+            Some(&json!("none"))
+        ));
+        assert_eq!(messages.0.len(), 1);
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+use ring::{digest, hmac};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// genUploadInfo 返回的上传凭证，文件通过临时密钥直接上传到腾讯云 COS
+// 不实现 Debug，避免临时密钥出现在日志中
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadInfo {
+    bucket_name: String,
+    region: String,
+    location: String,
+    encrypt_tmp_secret_id: String,
+    encrypt_tmp_secret_key: String,
+    encrypt_token: String,
+    #[serde(default)]
+    start_time: Option<u64>,
+    #[serde(default)]
+    expired_time: Option<u64>,
+    resource_url: String,
+}
+
+// 读取客户端给出的图片地址时的限制
+pub struct ImageFetch {
+    // 图片最大的字节数，data URL 解码后的大小也受此限制
+    pub max_bytes: usize,
+    // 单张图片下载的超时时间
+    pub timeout: Duration,
+    // 是否允许下载本机、内网和链路本地地址的图片
+    pub allow_private: bool,
+}
+
+// 下载或解码后的图片
+#[derive(Debug)]
+struct Image {
+    bytes: Vec<u8>,
+    mime: String,
+}
+
+// 上传一张图片，返回元宝请求体中 multimedia 的一项；
+// account_client 带有账号 Cookie，只用来申请上传凭证，其余请求使用 client
+pub async fn upload_image(
+    account_client: &Client,
+    client: &Client,
+    upload_info_url: &str,
+    fetch: &ImageFetch,
+    url: &str,
+) -> Result<Value> {
+    let image = load_image(fetch, url).await?;
+    let ext = image.mime.strip_prefix("image/").unwrap_or("png");
+    let file_name = format!("{}.{}", uuid::Uuid::new_v4().simple(), ext);
+    let info: UploadInfo = account_client
+        .post(upload_info_url)
+        .json(&json!({"fileName": file_name, "docFrom": "localDoc", "docOpenId": ""}))
+        .send()
+        .await
+        .context("cannot request upload info")?
+        .error_for_status()
+        .context("upstream rejected the upload info request")?
+        .json()
+        .await
+        .context("cannot parse upload info")?;
+
+    let host = format!("{}.cos.{}.myqcloud.com", info.bucket_name, info.region);
+    let authorization = cos_authorization(&info, &host);
+    let size = image.bytes.len();
+    // COS 不需要元宝的 Cookie
+    client
+        .put(format!("https://{}{}", host, info.location))
+        .header("Authorization", authorization)
+        .header("x-cos-security-token", &info.encrypt_token)
+        .header("Content-Type", &image.mime)
+        .body(image.bytes)
+        .send()
+        .await
+        .context("cannot upload image")?
+        .error_for_status()
+        .context("COS rejected the image upload")?;
+
+    Ok(json!({
+        "type": "image",
+        "docType": "image",
+        "url": info.resource_url,
+        "fileName": file_name,
+        "size": size,
+    }))
+}
+
+// 读取图片：data URL 直接解码，其余的地址下载
+async fn load_image(fetch: &ImageFetch, url: &str) -> Result<Image> {
+    if let Some(data) = url.strip_prefix("data:") {
+        let (meta, payload) = data.split_once(',').context("invalid data URL")?;
+        let Some(mime) = meta.strip_suffix(";base64") else {
+            bail!("only base64 data URLs are supported");
+        };
+        // 解码前按 base64 的长度估算大小，避免解码过大的数据
+        if payload.trim().len() / 4 * 3 > fetch.max_bytes + 2 {
+            bail!("image is larger than {} bytes", fetch.max_bytes);
+        }
+        let bytes = STANDARD
+            .decode(payload.trim())
+            .context("cannot decode base64 image")?;
+        if bytes.len() > fetch.max_bytes {
+            bail!("image is larger than {} bytes", fetch.max_bytes);
+        }
+        return Ok(Image {
+            bytes,
+            mime: mime.to_string(),
+        });
+    }
+    download_image(fetch, url).await
+}
+
+// 下载图片：只允许 http 和 https，解析出的地址不能是本机或内网地址；
+// 连接固定到检查过的地址并且不跟随重定向，避免通过 DNS 或重定向绕过检查
+async fn download_image(fetch: &ImageFetch, url: &str) -> Result<Image> {
+    let parsed = Url::parse(url).context("invalid image URL")?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("image URL must use http or https");
+    }
+    let host = parsed.host_str().context("image URL has no host")?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addr = resolve(host, port).await?;
+    if !fetch.allow_private && is_private(addr.ip()) {
+        bail!("image URL points to a private address {}", addr.ip());
+    }
+    let mut client = Client::builder()
+        .redirect(Policy::none())
+        .no_proxy()
+        .timeout(fetch.timeout);
+    if let Some(domain) = parsed.domain() {
+        client = client.resolve(domain, addr);
+    }
+    let mut response = client
+        .build()
+        .context("cannot build image client")?
+        .get(parsed)
+        .send()
+        .await
+        .context("cannot download image")?;
+    // 重定向也视为失败
+    if !response.status().is_success() {
+        bail!("cannot download image: {}", response.status());
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > fetch.max_bytes as u64)
+    {
+        bail!("image is larger than {} bytes", fetch.max_bytes);
+    }
+    let mime = response
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .filter(|mime| mime.starts_with("image/"))
+        .unwrap_or("image/png")
+        .to_string();
+    // Content-Length 可能缺失或不准确，边读边检查大小
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.context("cannot download image")? {
+        if bytes.len() + chunk.len() > fetch.max_bytes {
+            bail!("image is larger than {} bytes", fetch.max_bytes);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Image { bytes, mime })
+}
+
+// 解析主机名；解析出多个地址时其中有内网地址就使用它，使检查更严格
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("cannot resolve image host {}", host))?
+        .collect();
+    addrs
+        .iter()
+        .find(|addr| is_private(addr.ip()))
+        .or(addrs.first())
+        .copied()
+        .with_context(|| format!("cannot resolve image host {}", host))
+}
+
+// 本机、内网、链路本地（含云服务的元数据地址）等不应由代理访问的地址
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // 100.64.0.0/10 运营商级 NAT
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // fc00::/7 唯一本地地址和 fe80::/10 链路本地地址
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+// 按 COS 的 v5 签名算法生成 Authorization 头，只签名 host
+fn cos_authorization(info: &UploadInfo, host: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let key_time = format!(
+        "{};{}",
+        info.start_time.unwrap_or(now),
+        info.expired_time.unwrap_or(now + 3600)
+    );
+    let sign_key = hmac_sha1_hex(info.encrypt_tmp_secret_key.as_bytes(), &key_time);
+    let http_string = format!("put\n{}\n\nhost={}\n", info.location, host);
+    let string_to_sign = format!(
+        "sha1\n{}\n{}\n",
+        key_time,
+        hex(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, http_string.as_bytes()).as_ref())
+    );
+    let signature = hmac_sha1_hex(sign_key.as_bytes(), &string_to_sign);
+    format!(
+        "q-sign-algorithm=sha1&q-ak={}&q-sign-time={}&q-key-time={}&q-header-list=host&q-url-param-list=&q-signature={}",
+        info.encrypt_tmp_secret_id, key_time, key_time, signature
+    )
+}
+
+fn hmac_sha1_hex(key: &[u8], message: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    hex(hmac::sign(&key, message.as_bytes()).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::get;
+    use futures::StreamExt;
+    use std::future::IntoFuture;
+    use tokio::net::TcpListener;
+
+    fn fetch(allow_private: bool) -> ImageFetch {
+        ImageFetch {
+            max_bytes: 8,
+            timeout: Duration::from_millis(500),
+            allow_private,
+        }
+    }
+
+    // 在本机随机端口上启动返回图片的桩服务，返回它的地址
+    async fn serve() -> String {
+        let router = Router::new()
+            .route(
+                "/small.jpg",
+                get(|| async { ([("Content-Type", "image/jpeg")], "1234") }),
+            )
+            .route("/large.png", get(|| async { "0123456789" }))
+            .route(
+                "/chunked.png",
+                get(|| async {
+                    let chunks =
+                        futures::stream::iter(["01234", "56789"]).map(Ok::<_, std::io::Error>);
+                    axum::body::Body::from_stream(chunks)
+                }),
+            )
+            .route(
+                "/slow.png",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "1234"
+                }),
+            )
+            .route(
+                "/redirect.png",
+                get(|| async { (axum::http::StatusCode::FOUND, [("Location", "/small.jpg")]) }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, router).into_future());
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn data_urls_are_decoded_within_the_limit() {
+        let image = load_image(&fetch(false), "data:image/gif;base64,MTIzNA==")
+            .await
+            .unwrap();
+        assert_eq!(image.bytes, b"1234");
+        assert_eq!(image.mime, "image/gif");
+        let large = format!("data:image/png;base64,{}", STANDARD.encode("0123456789"));
+        assert!(load_image(&fetch(false), &large).await.is_err());
+        assert!(
+            load_image(&fetch(false), "data:image/png,1234")
+                .await
+                .is_err()
+        );
+        assert!(
+            load_image(&fetch(false), "data:image/png;base64,!!")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn urls_are_downloaded_within_the_limit() {
+        let base = serve().await;
+        let image = load_image(&fetch(true), &format!("{base}/small.jpg"))
+            .await
+            .unwrap();
+        assert_eq!(image.bytes, b"1234");
+        assert_eq!(image.mime, "image/jpeg");
+        // Content-Length 超出限制，以及没有 Content-Length 但内容超出限制
+        for path in ["large.png", "chunked.png"] {
+            let err = load_image(&fetch(true), &format!("{base}/{path}"))
+                .await
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("larger than 8 bytes"),
+                "{path}: {err}"
+            );
+        }
+        assert!(
+            load_image(&fetch(true), &format!("{base}/slow.png"))
+                .await
+                .is_err()
+        );
+        // 不跟随重定向
+        assert!(
+            load_image(&fetch(true), &format!("{base}/redirect.png"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn private_and_non_http_urls_are_rejected() {
+        let base = serve().await;
+        for url in [
+            format!("{base}/small.jpg"),
+            "http://localhost:1/a.png".to_string(),
+            "http://169.254.169.254/latest/meta-data".to_string(),
+            "http://10.0.0.1/a.png".to_string(),
+            "http://[::1]:1/a.png".to_string(),
+            "http://[::ffff:127.0.0.1]:1/a.png".to_string(),
+        ] {
+            let err = load_image(&fetch(false), &url).await.unwrap_err();
+            assert!(err.to_string().contains("private address"), "{url}: {err}");
+        }
+        for url in ["file:///etc/passwd", "ftp://example.com/a.png"] {
+            let err = load_image(&fetch(true), url).await.unwrap_err();
+            assert!(err.to_string().contains("http or https"), "{url}: {err}");
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in ["1.1.1.1", "8.8.8.8", "2606:4700::1111", "100.128.0.1"] {
+            assert!(!is_private(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "100.64.0.1",
+            "192.168.1.1",
+            "172.16.0.1",
+            "fd00::1",
+            "fe80::1",
+            "0.0.0.0",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
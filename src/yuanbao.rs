@@ -1,14 +1,29 @@
-use anyhow::{Context, Error, bail};
-use async_channel::{Receiver, Sender, unbounded};
-use reqwest::Client;
-use reqwest::header::{HeaderMap, HeaderName};
+use crate::account::{Account, AccountConfig, AccountPool, UnknownAccount, account_name};
+use crate::breaker::CircuitBreaker;
+use crate::metrics::METRICS;
+use crate::quota::{QuotaExceeded, QuotaTracker, QuotaUsage};
+use crate::ratelimit::RateLimiter;
+use crate::service::{Config, ModelConfig};
+use crate::session::{Session, SessionStore};
+use crate::upload::{ImageFetch, upload_image};
+use anyhow::{Context, Error, anyhow, bail};
+use async_channel::{Receiver, Sender, bounded};
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode};
 use reqwest_eventsource::{Event, EventSource};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::select;
-use tracing::{debug, warn, info};
+use tokio::time::{sleep, sleep_until};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{Instrument, debug, info, instrument, warn};
+use uuid::Uuid;
 
 // 定义聊天完成事件的枚举
 #[derive(Debug)]
@@ -33,44 +48,643 @@ pub enum ChatCompletionMessageType {
 }
 
 // 定义聊天请求的结构
+#[derive(Clone)]
 pub struct ChatCompletionRequest {
     pub messages: ChatMessages,
-    pub chat_model: ChatModel,
+    pub chat_model: ResolvedModel,
+    // 会话标识，开启会话映射时接着该会话对应的对话
+    pub session: Option<String>,
+    // 客户端指定的账号，不指定时轮询选择
+    pub account: Option<String>,
+    // 需要模型接着续写的助手回答开头
+    pub prefill: Option<String>,
+    // 客户端指定的采样参数
+    pub sampling: SamplingParams,
+    // 正文最多的 token 数，达到后以 length 结束
+    pub max_tokens: Option<u32>,
+    // 停止序列，生成的正文遇到任意一个时截断并结束
+    pub stop: Vec<String>,
+    // 是否要求模型只输出 JSON
+    pub json_mode: bool,
+    // 客户端指定的元宝插件，不指定时使用配置中的
+    pub plugin: Option<String>,
+}
+
+// temperature 允许的取值范围
+const TEMPERATURE_RANGE: (f32, f32) = (0.0, 2.0);
+
+// 采样参数，未设置的项不发送给上游
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+pub struct SamplingParams {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
 }
 
+impl SamplingParams {
+    // 未设置的项使用 defaults 中的值
+    pub fn or(self, defaults: SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+        }
+    }
+
+    // 将 temperature 限制在 OpenAI 允许的范围内，超出时记录警告
+    fn clamped_temperature(&self) -> Option<f32> {
+        let temperature = self.temperature?;
+        if temperature.is_nan() {
+            warn!("Ignoring invalid temperature {}", temperature);
+            return None;
+        }
+        let clamped = temperature.clamp(TEMPERATURE_RANGE.0, TEMPERATURE_RANGE.1);
+        if clamped != temperature {
+            warn!(
+                "Temperature {} is out of range, using {}",
+                temperature, clamped
+            );
+        }
+        Some(clamped)
+    }
+
+    // 将已设置的参数写入上游请求体
+    fn apply(&self, body: &mut serde_json::Value) {
+        let params = [
+            ("temperature", self.clamped_temperature()),
+            ("topP", self.top_p),
+            ("frequencyPenalty", self.frequency_penalty),
+            ("presencePenalty", self.presence_penalty),
+        ];
+        for (key, value) in params {
+            if let Some(value) = value {
+                body[key] = json!(value);
+            }
+        }
+    }
+}
+
+// 请求中允许的消息角色
+const MESSAGE_ROLES: [&str; 6] = [
+    "system",
+    "developer",
+    "user",
+    "assistant",
+    "tool",
+    "function",
+];
+
 // 定义一组聊天消息
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ChatMessages(pub Vec<ChatMessage>);
 
 // 定义单个聊天消息的结构
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "RawChatMessage")]
 pub struct ChatMessage {
     pub role: String,
     pub content: Option<String>,
     pub reasoning_content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+    // 消息中图片的地址（URL 或 data URL）
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+// 请求中的消息，content 可以是字符串或多段内容的数组
+#[derive(Deserialize)]
+struct RawChatMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<MessageContent>,
+    #[serde(default)]
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    images: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "content must be a string or an array of content parts"
+)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
 }
 
-// 实现 ChatMessages 的 Display trait 用于打印消息
-impl Display for ChatMessages {
+// 多段内容中的一段，只支持文本和图片
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl From<RawChatMessage> for ChatMessage {
+    // 文本段依次拼接为 content，图片单独取出
+    fn from(raw: RawChatMessage) -> ChatMessage {
+        let mut images = raw.images;
+        let content = match raw.content {
+            None => None,
+            Some(MessageContent::Text(text)) => Some(text),
+            Some(MessageContent::Parts(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match part {
+                        ContentPart::Text { text } => texts.push(text),
+                        ContentPart::ImageUrl { image_url } => images.push(image_url.url),
+                        ContentPart::Other => {}
+                    }
+                }
+                Some(texts.join("\n"))
+            }
+        };
+        ChatMessage {
+            role: raw.role,
+            content,
+            reasoning_content: raw.reasoning_content,
+            tool_calls: raw.tool_calls,
+            images,
+        }
+    }
+}
+
+// 助手消息同时带有 content 和 reasoning_content 时的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayReasoning {
+    // 用 <think> 标签包裹后放在 content 之前
+    Prepend,
+    // 用 <think> 标签包裹后放在 content 之后
+    Append,
+    // 丢弃推理内容
+    #[default]
+    Drop,
+}
+
+// 日志中 prompt 等用户内容的输出方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogPromptMode {
+    // 原样输出
+    Full,
+    // 只输出长度
+    #[default]
+    LengthOnly,
+    // 输出长度和稳定的哈希值，相同内容可以互相关联
+    Hashed,
+    // 完全不输出
+    None,
+}
+
+impl LogPromptMode {
+    // 按配置生成可以写入日志的内容
+    pub fn display(self, text: &str) -> String {
+        match self {
+            LogPromptMode::Full => text.to_string(),
+            LogPromptMode::LengthOnly => format!("<{} chars>", text.chars().count()),
+            LogPromptMode::Hashed => format!(
+                "<{} chars, fnv1a:{:016x}>",
+                text.chars().count(),
+                fnv1a(text.as_bytes())
+            ),
+            LogPromptMode::None => "<omitted>".to_string(),
+        }
+    }
+}
+
+// 隐藏代理地址中的用户名和密码，用于日志输出
+pub fn redact_proxy(proxy: &str) -> String {
+    match reqwest::Url::parse(proxy) {
+        Ok(mut url) if !url.username().is_empty() || url.password().is_some() => {
+            let _ = url.set_username("***");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Ok(url) => url.to_string(),
+        Err(_) => "<invalid>".to_string(),
+    }
+}
+
+// 64 位 FNV-1a 哈希，结果不随进程或编译器版本变化
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// 粗略估算文本的 token 数：中日韩字符每个算一个，其余字符约四个算一个
+pub fn estimate_tokens(text: &str) -> u64 {
+    let mut tokens = 0;
+    let mut others: u64 = 0;
+    for c in text.chars() {
+        if c as u32 >= 0x2E80 {
+            tokens += 1;
+        } else {
+            others += 1;
+        }
+    }
+    tokens + others.div_ceil(4)
+}
+
+// 截取 text 的最长前缀，使其估算的 token 数不超过 budget
+fn truncate_to_tokens(text: &str, budget: u64) -> &str {
+    let mut end = 0;
+    for (index, c) in text.char_indices() {
+        let next = index + c.len_utf8();
+        if estimate_tokens(&text[..next]) > budget {
+            break;
+        }
+        end = next;
+    }
+    &text[..end]
+}
+
+// 最后一条用户消息为空时的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyUserTurn {
+    // 替换为配置的提示语
+    #[default]
+    Nudge,
+    // 返回 400 错误
+    Reject,
+}
+
+// 上游没有返回正文时的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyOutput {
+    // 原样返回空的回答
+    #[default]
+    Keep,
+    // 返回配置的占位文本
+    Placeholder,
+    // 返回错误
+    Error,
+}
+
+// 上游没有返回任何正文
+#[derive(Debug)]
+pub struct EmptyCompletion;
+
+impl Display for EmptyCompletion {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let arr = &self.0;
-        if arr.is_empty() {
-            return Err(std::fmt::Error);
+        write!(f, "upstream returned an empty completion")
+    }
+}
+
+impl std::error::Error for EmptyCompletion {}
+
+// 未开启工具模拟时，工具相关消息的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolMessages {
+    // 丢弃工具调用和工具结果
+    #[default]
+    Drop,
+    // 将工具调用和结果概括为普通文本
+    Summarize,
+}
+
+// system 消息的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemMessages {
+    // 合并所有 system 消息，作为一段指令放在最前面
+    #[default]
+    Merge,
+    // 保持原来的位置，和其他消息一样渲染
+    Inline,
+    // 丢弃所有 system 消息
+    Drop,
+}
+
+// 上游 text 事件中 msg 的含义
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextStreamMode {
+    // 根据内容自动判断：新的 msg 以之前收到的全部正文开头时按累积处理
+    #[default]
+    Auto,
+    // 每个 msg 都是新增的部分
+    Incremental,
+    // 每个 msg 都是到目前为止的全部正文
+    Cumulative,
+}
+
+// prompt 超过长度限制时的处理方式
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptOverflow {
+    // 返回 400
+    #[default]
+    Reject,
+    // 从最早的消息开始丢弃，直到不超过限制
+    Truncate,
+}
+
+impl ChatMessages {
+    // 按 mode 整理 system 消息
+    pub fn arrange_system(&mut self, mode: SystemMessages) {
+        if mode == SystemMessages::Inline {
+            return;
         }
-        if arr.len() == 1 {
-            write!(f, "{}", arr[0].content.as_ref().unwrap_or(&"".to_string()))?;
-            return Ok(());
+        let (system, others): (Vec<ChatMessage>, Vec<ChatMessage>) = std::mem::take(&mut self.0)
+            .into_iter()
+            .partition(|item| item.role.trim() == "system");
+        self.0 = others;
+        if mode == SystemMessages::Drop {
+            return;
+        }
+        let instructions: Vec<String> = system
+            .into_iter()
+            .filter_map(|item| item.content)
+            .filter(|content| !content.trim().is_empty())
+            .collect();
+        if !instructions.is_empty() {
+            self.0.insert(
+                0,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: Some(instructions.join("\n\n")),
+                    reasoning_content: None,
+                    tool_calls: None,
+                    images: Vec::new(),
+                },
+            );
+        }
+    }
+
+    // 把配置的 system 提示词放在最前面；replace 为 true 时丢弃客户端的 system 消息
+    pub fn apply_system_prompt(&mut self, prompt: &str, replace: bool) {
+        if prompt.trim().is_empty() {
+            return;
+        }
+        if replace {
+            self.0.retain(|item| item.role.trim() != "system");
         }
-        for item in arr {
-            write!(
-                f,
-                "#[{}]\n{}\n\n",
-                item.role.trim(),
-                item.content.as_ref().unwrap_or(&"".to_string()).trim()
-            )?;
+        // 已经合并出一段 system 指令时接在它前面，否则单独插入一条
+        if let Some(first) = self.0.first_mut()
+            && first.role.trim() == "system"
+            && let Some(content) = &mut first.content
+        {
+            *content = format!("{}\n\n{}", prompt, content);
+            return;
+        }
+        self.0.insert(
+            0,
+            ChatMessage {
+                role: "system".to_string(),
+                content: Some(prompt.to_string()),
+                reasoning_content: None,
+                tool_calls: None,
+                images: Vec::new(),
+            },
+        );
+    }
+
+    // 清理 tool/function 角色的消息和助手消息中的 tool_calls，避免污染 prompt
+    pub fn strip_tools(&mut self, mode: ToolMessages) {
+        let messages = std::mem::take(&mut self.0);
+        for mut item in messages {
+            let calls = item.tool_calls.take().unwrap_or_default();
+            let role = item.role.trim();
+            if role == "tool" || role == "function" {
+                if mode == ToolMessages::Summarize {
+                    let result = item.content.as_deref().unwrap_or("").trim();
+                    item.role = "user".to_string();
+                    item.content = Some(format!("（工具返回结果：{}）", result));
+                    self.0.push(item);
+                }
+                continue;
+            }
+            if mode == ToolMessages::Summarize && !calls.is_empty() {
+                let names: Vec<&str> = calls
+                    .iter()
+                    .filter_map(|call| call["function"]["name"].as_str())
+                    .collect();
+                let note = format!("（调用了工具：{}）", names.join("、"));
+                item.content = Some(match item.content.as_deref().map(str::trim) {
+                    Some(content) if !content.is_empty() => format!("{}\n{}", content, note),
+                    _ => note,
+                });
+            }
+            // 只有 tool_calls 没有正文的助手消息直接去掉
+            if item.content.as_deref().unwrap_or("").trim().is_empty() && !calls.is_empty() {
+                continue;
+            }
+            self.0.push(item);
+        }
+    }
+
+    // 检查请求中的消息，返回指出具体字段的错误信息
+    pub fn validate(&self) -> Result<(), String> {
+        if self.0.is_empty() {
+            return Err("messages must not be empty".to_string());
+        }
+        for (index, item) in self.0.iter().enumerate() {
+            let role = item.role.trim();
+            if !MESSAGE_ROLES.contains(&role) {
+                return Err(format!(
+                    "messages[{}].role '{}' is not one of {}",
+                    index,
+                    item.role,
+                    MESSAGE_ROLES.join(", ")
+                ));
+            }
+            // 只有带工具调用的助手消息可以没有 content
+            if item.content.is_none() && item.images.is_empty() && item.tool_calls.is_none() {
+                return Err(format!("messages[{}].content is required", index));
+            }
         }
         Ok(())
     }
+
+    // 最后一条助手消息之后的消息，即上游对话中还没有的部分；没有新消息时返回全部消息
+    pub fn after_last_assistant(&self) -> ChatMessages {
+        let start = self
+            .0
+            .iter()
+            .rposition(|item| item.role.trim() == "assistant")
+            .map_or(0, |index| index + 1);
+        if start >= self.0.len() {
+            return self.clone();
+        }
+        ChatMessages(self.0[start..].to_vec())
+    }
+
+    // 取出末尾未完成的助手消息，作为需要续写的开头
+    pub fn take_assistant_prefill(&mut self) -> Option<String> {
+        let last = self.0.last()?;
+        let content = last.content.as_deref().unwrap_or("");
+        if last.role.trim() != "assistant" || content.trim().is_empty() || self.0.len() < 2 {
+            return None;
+        }
+        let content = content.to_string();
+        self.0.pop();
+        Some(content)
+    }
+
+    // 判断最后一条消息是否为空的用户消息
+    pub fn has_empty_user_tail(&self) -> bool {
+        self.0.last().is_some_and(|item| {
+            item.role.trim() == "user"
+                && item.content.as_deref().unwrap_or("").trim().is_empty()
+                && item.images.is_empty()
+        })
+    }
+
+    // 将空的最后一条用户消息替换为提示语
+    pub fn fill_empty_user_tail(&mut self, nudge: &str) {
+        if self.has_empty_user_tail()
+            && let Some(item) = self.0.last_mut()
+        {
+            item.content = Some(nudge.to_string());
+        }
+    }
+
+    // 按日志配置描述消息结构，用于日志输出
+    pub fn describe(&self, mode: LogPromptMode) -> String {
+        let parts: Vec<String> = self
+            .0
+            .iter()
+            .map(|item| {
+                format!(
+                    "{} {}",
+                    item.role.trim(),
+                    mode.display(item.content.as_deref().unwrap_or(""))
+                )
+            })
+            .collect();
+        format!("[{}]", parts.join(", "))
+    }
+}
+
+impl ChatMessage {
+    // 获取用于拼接 prompt 的消息内容
+    fn prompt_content(&self, replay_reasoning: ReplayReasoning) -> String {
+        let content = self.content.as_deref().unwrap_or("").trim();
+        let reasoning = match &self.reasoning_content {
+            Some(reasoning) if self.role.trim() == "assistant" && !reasoning.trim().is_empty() => {
+                reasoning.trim()
+            }
+            _ => return content.to_string(),
+        };
+        match replay_reasoning {
+            ReplayReasoning::Prepend => format!("<think>\n{}\n</think>\n\n{}", reasoning, content),
+            ReplayReasoning::Append => format!("{}\n\n<think>\n{}\n</think>", content, reasoning),
+            ReplayReasoning::Drop => content.to_string(),
+        }
+    }
+}
+
+// 多条消息拼接为 prompt 时每条消息的默认格式
+pub const DEFAULT_PROMPT_TEMPLATE: &str = "#[{role}]\n{content}\n\n";
+
+// 用模板拼接一条消息；先替换角色，内容中的 {role} 不会被替换
+fn render_turn(template: &str, role: &str, content: &str) -> String {
+    template
+        .replace("{role}", role)
+        .replace("{content}", content)
+}
+
+// 用模板拼接一条未完成的消息，只保留模板中 {content} 之前的部分，让模型接着 content 往下写
+fn render_open_turn(template: &str, role: &str, content: &str) -> String {
+    let head = template.split("{content}").next().unwrap_or("");
+    head.replace("{role}", role) + content
+}
+
+// 在 prompt 之后追加已经拼接好的消息
+fn append_turns(prompt: &str, turns: &str) -> String {
+    format!("{}\n\n{}", prompt.trim_end(), turns)
+}
+
+// 重连时发送的 prompt：已经输出过的内容作为助手消息附上并请求续写；还没有输出时原样重发
+fn reconnect_prompt(prompt: &str, template: &str, emitted: &str) -> String {
+    if emitted.is_empty() {
+        return prompt.to_string();
+    }
+    let turns = render_turn(template, "assistant", emitted)
+        + &render_turn(template, "user", CONTINUATION_PROMPT);
+    append_turns(prompt, &turns)
+}
+
+// 没有任何消息，无法拼接 prompt
+#[derive(Debug)]
+pub struct EmptyMessages;
+
+impl Display for EmptyMessages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot build a prompt from empty messages")
+    }
+}
+
+impl std::error::Error for EmptyMessages {}
+
+impl ChatMessages {
+    // 将消息拼接为发送给 Yuanbao 的 prompt：只有一条消息时直接使用它的内容，不带角色；
+    // 多条消息时每条按 template 的格式拼接
+    pub fn render(
+        &self,
+        replay_reasoning: ReplayReasoning,
+        template: &str,
+    ) -> Result<String, EmptyMessages> {
+        match self.0.as_slice() {
+            [] => Err(EmptyMessages),
+            [only] => Ok(only.prompt_content(replay_reasoning)),
+            items => Ok(items
+                .iter()
+                .map(|item| {
+                    render_turn(
+                        template,
+                        item.role.trim(),
+                        &item.prompt_content(replay_reasoning),
+                    )
+                })
+                .collect()),
+        }
+    }
+}
+
+impl ChatMessages {
+    // 丢弃最早的一条非 system 消息，只剩最后一条时不再丢弃
+    pub fn drop_oldest(&mut self) -> bool {
+        let others = self
+            .0
+            .iter()
+            .filter(|item| item.role.trim() != "system")
+            .count();
+        if others <= 1 {
+            return false;
+        }
+        let Some(index) = self.0.iter().position(|item| item.role.trim() != "system") else {
+            return false;
+        };
+        self.0.remove(index);
+        true
+    }
 }
 
 // 定义聊天模型的枚举
@@ -78,6 +692,8 @@ impl Display for ChatMessages {
 pub enum ChatModel {
     DeepSeekV3,
     DeepSeekR1,
+    HunyuanTurbo,
+    HunyuanT1,
 }
 
 impl FromStr for ChatModel {
@@ -87,148 +703,1322 @@ impl FromStr for ChatModel {
         match s {
             "deepseek-r1" => Ok(ChatModel::DeepSeekR1),
             "deepseek-v3" => Ok(ChatModel::DeepSeekV3),
+            "hunyuan-turbo" => Ok(ChatModel::HunyuanTurbo),
+            "hunyuan-t1" => Ok(ChatModel::HunyuanT1),
             &_ => {
                 bail!("invalid model")
             }
         }
     }
-}
+}
+
+impl ChatModel {
+    // 所有支持的模型
+    pub const ALL: [ChatModel; 4] = [
+        ChatModel::DeepSeekV3,
+        ChatModel::DeepSeekR1,
+        ChatModel::HunyuanTurbo,
+        ChatModel::HunyuanT1,
+    ];
+
+    // 转换为 Yuanbao API 需要的字符串格式
+    pub fn as_yuanbao_string(&self) -> String {
+        match self {
+            ChatModel::DeepSeekV3 => "deep_seek_v3",
+            ChatModel::DeepSeekR1 => "deep_seek",
+            ChatModel::HunyuanTurbo => "hunyuan_gpt_175B_0404",
+            ChatModel::HunyuanT1 => "hunyuan_t1",
+        }
+        .to_string()
+    }
+
+    // 转换为常见的模型字符串格式
+    pub fn as_common_string(&self) -> String {
+        match self {
+            ChatModel::DeepSeekV3 => "deepseek-v3",
+            ChatModel::DeepSeekR1 => "deepseek-r1",
+            ChatModel::HunyuanTurbo => "hunyuan-turbo",
+            ChatModel::HunyuanT1 => "hunyuan-t1",
+        }
+        .to_string()
+    }
+}
+
+// 解析后的模型：模型名（用于查找模型配置）和上游的 chatModelId
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedModel {
+    pub name: String,
+    pub chat_model_id: String,
+}
+
+impl From<ChatModel> for ResolvedModel {
+    fn from(model: ChatModel) -> ResolvedModel {
+        ResolvedModel {
+            name: model.as_common_string(),
+            chat_model_id: model.as_yuanbao_string(),
+        }
+    }
+}
+
+// 未配置 user_agent 时使用的 User-Agent
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64)\
+     AppleWebKit/537.36 (KHTML, like Gecko) Chrome/134.0.0.0 Safari/537.36";
+
+// 上游中途断开后最多重连的次数
+const MAX_RECONNECTS: usize = 2;
+
+// 重连时追加的续写提示
+const CONTINUATION_PROMPT: &str =
+    "你上面的回答被中断了，请从中断处继续输出，不要重复已经输出的内容。";
+
+// 续写助手回答时附加的提示
+const PREFILL_PROMPT: &str = "请紧接着下面 assistant 未完成的回答继续输出，不要重复已有的内容。";
+
+// 要求输出 JSON 时附加的提示
+const JSON_MODE_PROMPT: &str =
+    "请只输出一个合法的 JSON 对象，不要使用 Markdown 代码块，也不要输出任何其他文字。";
+
+// 重试等待时间的上限
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+// 在退避时间的一半到全部之间随机取值，避免多个请求同时重试
+fn jitter(backoff: Duration) -> Duration {
+    let random = (Uuid::new_v4().as_u128() as u64) as f64 / u64::MAX as f64;
+    backoff.mul_f64(0.5 + random / 2.0)
+}
+
+// 上游超时结束时的 finish_reason，OpenAI 没有表示超时的值，用 length 表示回答被截断
+const TIMEOUT_FINISH_REASON: &str = "length";
+
+// SSE 事件流的退出方式
+enum SseExit {
+    // 正常结束，携带 finish_reason
+    Finish(String),
+    // 上游中途断开（如 HTTP/2 GOAWAY），可以重连续写
+    Reconnect,
+    // 还没有输出内容时连接失败，可以原样重试
+    Retry(Error),
+}
+
+// 判断错误是否由 HTTP/2 GOAWAY 引起
+fn is_go_away(err: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(e) = source {
+        if let Some(h2_err) = e.downcast_ref::<h2::Error>() {
+            return h2_err.is_go_away();
+        }
+        source = e.source();
+    }
+    false
+}
+
+// 上游返回的不是事件流，通常意味着凭证已失效
+#[derive(Debug)]
+pub struct CredentialsExpired(pub String);
+
+impl Display for CredentialsExpired {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upstream session expired: upstream returned '{}' instead of an event stream; refresh hy_user and hy_token",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for CredentialsExpired {}
+
+// 上游在事件流中返回的错误，如内容审核拦截或额度提示
+#[derive(Debug)]
+pub struct UpstreamError(pub String);
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream returned an error: {}", self.0)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+// 重试多次后上游仍然不可用（连接失败、429、502、503、504）
+#[derive(Debug)]
+pub struct UpstreamUnavailable;
+
+impl Display for UpstreamUnavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream is unavailable")
+    }
+}
+
+// 上游返回 429，重试多次后仍然被限流
+#[derive(Debug)]
+pub struct UpstreamRateLimited;
+
+impl Display for UpstreamRateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream rate limit reached (429 Too Many Requests)")
+    }
+}
+
+impl std::error::Error for UpstreamRateLimited {}
+
+// 换一个账号可能成功的错误
+fn is_failover_error(err: &anyhow::Error) -> bool {
+    err.is::<CredentialsExpired>() || err.is::<UpstreamUnavailable>()
+}
+
+// 错误事件中最多保留的原文长度
+const UPSTREAM_ERROR_MAX_CHARS: usize = 200;
+
+// 从错误事件中取出错误信息，无法识别时使用原文
+fn upstream_error_message(data: &str) -> String {
+    let value: serde_json::Value = serde_json::from_str(data).unwrap_or_default();
+    let message = ["msg", "message", "errMsg", "error"]
+        .iter()
+        .find_map(|key| {
+            let field = &value[*key];
+            field.as_str().or_else(|| field["message"].as_str())
+        })
+        .filter(|message| !message.is_empty())
+        .unwrap_or(data.trim());
+    message.chars().take(UPSTREAM_ERROR_MAX_CHARS).collect()
+}
+
+// 缓存的 JSON 片段最多的字节数，超过后丢弃
+const MAX_FRAGMENT_BYTES: usize = 64 * 1024;
+
+// 拼接被拆在多个 SSE 事件中的 JSON
+#[derive(Default)]
+struct FragmentBuffer {
+    buffer: String,
+    // 已经缓存的片段数
+    count: usize,
+}
+
+impl FragmentBuffer {
+    // 解析一个事件的数据，无法解析时与之前的片段拼接；还不完整时返回 None
+    fn parse(&mut self, data: &str) -> Option<serde_json::Value> {
+        if !self.buffer.is_empty() {
+            let joined = std::mem::take(&mut self.buffer) + data;
+            if let Ok(value) = serde_json::from_str(&joined) {
+                debug!("Reassembled an event from {} fragments", self.count + 1);
+                self.count = 0;
+                return Some(value);
+            }
+            // 这个事件本身就是完整的 JSON 时，说明之前的片段无法补全
+            if let Ok(value) = serde_json::from_str(data) {
+                debug!(
+                    "Dropping {} unparseable bytes of event data",
+                    joined.len() - data.len()
+                );
+                self.count = 0;
+                return Some(value);
+            }
+            if joined.len() > MAX_FRAGMENT_BYTES {
+                debug!("Dropping {} unparseable bytes of event data", joined.len());
+                self.count = 0;
+                return None;
+            }
+            self.buffer = joined;
+            self.count += 1;
+            return None;
+        }
+        match serde_json::from_str(data) {
+            Ok(value) => Some(value),
+            // 只缓存像是 JSON 开头的数据，其他的非 JSON 事件照旧忽略
+            Err(_) if data.trim_start().starts_with('{') && data.len() <= MAX_FRAGMENT_BYTES => {
+                self.buffer = data.to_string();
+                self.count = 1;
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+// 单次补全请求在处理 SSE 过程中的状态
+struct StreamContext {
+    sender: Sender<ChatCompletionEvent>,
+    log_prompt_mode: LogPromptMode,
+    // 本次请求所用的账号
+    account: Arc<Account>,
+    // 已经发送给客户端的正文
+    emitted: String,
+    // 正文最多的 token 数和已经发送的 token 数
+    max_tokens: Option<u64>,
+    completion_tokens: u64,
+    // 是否已经向客户端输出了上游的内容，输出后不再原样重试
+    output_started: bool,
+    // 连接失败时最多重试的次数和首次重试前的等待时间
+    max_retries: usize,
+    retry_backoff: Duration,
+    // 上游没有返回内容时最多重试的次数，以及重试后仍然没有正文时的处理方式
+    max_empty_retries: usize,
+    empty_output: EmptyOutput,
+    empty_placeholder: String,
+    // 整个请求的截止时间，重连也不会延长
+    deadline: Option<Instant>,
+    // 两个事件之间最长的间隔
+    idle_timeout: Option<Duration>,
+    // 停止序列
+    stop: Vec<String>,
+    // 可能是停止序列开头、暂未发送的正文
+    pending: String,
+    // 上游 msg 的含义，自动判断出是累积的之后改为 Cumulative
+    text_mode: TextStreamMode,
+    // 本次连接中从上游收到的全部正文
+    upstream_text: String,
+    // 拼接消息的模板，重连时用它追加已输出的回答和续写提示
+    prompt_template: String,
+}
+
+impl StreamContext {
+    // 把上游的 msg 转换为新增的正文
+    fn text_delta(&mut self, msg: &str) -> String {
+        let cumulative = match self.text_mode {
+            TextStreamMode::Incremental => false,
+            TextStreamMode::Cumulative => msg.starts_with(self.upstream_text.as_str()),
+            TextStreamMode::Auto => {
+                let detected = !self.upstream_text.is_empty()
+                    && msg.len() > self.upstream_text.len()
+                    && msg.starts_with(self.upstream_text.as_str());
+                if detected {
+                    debug!("Upstream sends cumulative text, emitting only the new part");
+                    self.text_mode = TextStreamMode::Cumulative;
+                }
+                detected
+            }
+        };
+        if cumulative {
+            let delta = msg[self.upstream_text.len()..].to_string();
+            self.upstream_text = msg.to_string();
+            return delta;
+        }
+        if self.text_mode == TextStreamMode::Cumulative {
+            // 累积的正文与之前的不一致时，视为上游重新开始了回答
+            self.upstream_text = msg.to_string();
+        } else {
+            self.upstream_text.push_str(msg);
+        }
+        msg.to_string()
+    }
+
+    // 处理一段正文：遇到停止序列或达到 max_tokens 时截断，需要结束时返回 finish_reason
+    async fn send_text(&mut self, text: &str) -> anyhow::Result<Option<&'static str>> {
+        let mut text = std::mem::take(&mut self.pending) + text;
+        let mut finish_reason = None;
+        if let Some(index) = find_stop(&text, &self.stop) {
+            text.truncate(index);
+            finish_reason = Some("stop");
+        } else {
+            // 停止序列可能被拆在两个事件中，先保留结尾可能匹配的部分
+            let held = stop_prefix_len(&text, &self.stop);
+            self.pending = text.split_off(text.len() - held);
+        }
+        // 只统计正文，推理内容不计入 max_tokens
+        if let Some(max) = self.max_tokens
+            && self.completion_tokens + estimate_tokens(&text) >= max
+        {
+            text = truncate_to_tokens(&text, max - self.completion_tokens).to_string();
+            self.pending.clear();
+            info!("Reached max_tokens, stopping the stream");
+            finish_reason = Some("length");
+        }
+        self.emit(text).await?;
+        Ok(finish_reason)
+    }
+
+    // 发送暂时保留的正文
+    async fn flush_text(&mut self) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.emit(pending).await
+    }
+
+    // 客户端断开时直接结束；通过取消接口停止时发出缓存的正文，再以 stop 结束
+    async fn cancelled(&mut self) {
+        if self.sender.is_closed() {
+            info!("Client disconnected, closing the upstream stream");
+            return;
+        }
+        info!("Generation cancelled, closing the upstream stream");
+        let _ = self.flush_text().await;
+        let _ = self
+            .sender
+            .send(ChatCompletionEvent::Finish("stop".to_string()))
+            .await;
+    }
+
+    async fn emit(&mut self, text: String) -> anyhow::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let tokens = estimate_tokens(&text);
+        self.completion_tokens += tokens;
+        METRICS.tokens_emitted.fetch_add(tokens, Ordering::Relaxed);
+        self.emitted.push_str(&text);
+        self.sender
+            .send(ChatCompletionEvent::Message(ChatCompletionMessage {
+                r#type: ChatCompletionMessageType::Msg,
+                text,
+            }))
+            .await?;
+        Ok(())
+    }
+}
+
+// 将 Yuanbao 的 stopReason 转换为 OpenAI 的 finish_reason，未知的值按 stop 处理
+fn map_finish_reason(stop_reason: &str) -> &'static str {
+    let mapped = match stop_reason.to_ascii_lowercase().as_str() {
+        "stop" | "finish" | "finished" | "end" | "normal" => "stop",
+        "length" | "max_tokens" | "max_length" | "token_limit" | "too_long" => "length",
+        "sensitive" | "security" | "audit" | "content_filter" | "censor" | "moderation" => {
+            "content_filter"
+        }
+        _ => {
+            debug!("Unknown upstream stopReason '{}', using stop", stop_reason);
+            return "stop";
+        }
+    };
+    if mapped != stop_reason {
+        debug!(
+            "Mapped upstream stopReason '{}' to '{}'",
+            stop_reason, mapped
+        );
+    }
+    mapped
+}
+
+// 查找最早出现的停止序列的位置
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter().filter_map(|seq| text.find(seq.as_str())).min()
+}
+
+// text 结尾与某个停止序列开头重合的最大长度
+fn stop_prefix_len(text: &str, stop: &[String]) -> usize {
+    stop.iter()
+        .filter_map(|seq| {
+            (1..seq.len())
+                .rev()
+                .find(|&len| seq.is_char_boundary(len) && text.ends_with(&seq[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// Yuanbao 结构体，用于与 API 交互
+#[derive(Clone)]
+pub struct Yuanbao {
+    config: Config,
+    accounts: Arc<AccountPool>,
+    // 不带账号 Cookie 的客户端，用于上传图片等不需要登录的请求
+    client: Client,
+    quota: Arc<QuotaTracker>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    sessions: Option<Arc<SessionStore>>,
+    credential_check: Arc<Mutex<Option<CredentialCheck>>>,
+}
+
+// 最近一次凭证自检的时间和结果
+type CredentialCheck = (Instant, Result<(), String>);
+
+// 凭证自检结果的缓存时间，健康检查频繁调用时不会每次都请求上游
+const CREDENTIAL_CHECK_TTL: Duration = Duration::from_secs(30);
+
+// 发往上游的一次补全请求
+struct UpstreamRequest {
+    conversation_id: String,
+    url: String,
+    body: serde_json::Value,
+}
+
+// 本次请求新建的对话，请求结束（包括客户端断开）后在后台删除
+struct ConversationCleanup {
+    // 不需要删除时为 None
+    yuanbao: Option<Yuanbao>,
+    agent_id: String,
+    conversations: Vec<(Arc<Account>, String)>,
+}
+
+impl ConversationCleanup {
+    // 会话映射要接着使用对话，这类请求新建的对话不删除
+    fn new(yuanbao: &Yuanbao, agent_id: &str, request: &ChatCompletionRequest) -> Self {
+        let keep = yuanbao.sessions.is_some() && request.session.is_some();
+        ConversationCleanup {
+            yuanbao: (yuanbao.config.delete_conversations && !keep).then(|| yuanbao.clone()),
+            agent_id: agent_id.to_string(),
+            conversations: Vec::new(),
+        }
+    }
+
+    // 记录一个对话，固定的 conversation_id 不删除
+    fn track(&mut self, account: &Arc<Account>, conversation_id: &str) {
+        if let Some(yuanbao) = &self.yuanbao
+            && conversation_id != yuanbao.config.conversation_id
+        {
+            self.conversations
+                .push((account.clone(), conversation_id.to_string()));
+        }
+    }
+}
+
+impl Drop for ConversationCleanup {
+    fn drop(&mut self) {
+        let Some(yuanbao) = self.yuanbao.take() else {
+            return;
+        };
+        if self.conversations.is_empty() {
+            return;
+        }
+        let agent_id = std::mem::take(&mut self.agent_id);
+        let conversations = std::mem::take(&mut self.conversations);
+        // 删除失败只记录日志，不影响已经返回给客户端的结果
+        tokio::spawn(
+            async move {
+                for (account, conversation_id) in conversations {
+                    match yuanbao
+                        .delete_conversation(&account, &agent_id, &conversation_id)
+                        .await
+                    {
+                        Ok(()) => debug!("Deleted conversation {}", conversation_id),
+                        Err(err) => {
+                            warn!("Cannot delete conversation {}: {:#}", conversation_id, err)
+                        }
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+// 已经发往上游的补全请求
+pub struct Completion {
+    // 所用账号的名称
+    pub account: String,
+    pub receiver: Receiver<ChatCompletionEvent>,
+    // 丢弃时（客户端断开）立即停止读取上游
+    pub guard: DropGuard,
+    // 与 guard 对应的令牌，取消接口用它停止生成
+    pub cancel: CancellationToken,
+}
+
+impl Yuanbao {
+    // 创建一个新的 Yuanbao 实例
+    pub fn new(config: Config) -> Yuanbao {
+        let account_configs = config.account_configs();
+        let account_quotas = account_configs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, account)| {
+                let name = account_name(account, index, account_configs.len());
+                account.daily_quota.map(|quota| (name, quota))
+            })
+            .collect();
+        let quota = Arc::new(QuotaTracker::new(
+            config.daily_quota,
+            account_quotas,
+            config.quota_state_file.clone(),
+        ));
+        let rate_limiter = config
+            .upstream_rate_limit
+            .clone()
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        let breaker = config
+            .circuit_breaker
+            .clone()
+            .map(|breaker| Arc::new(CircuitBreaker::new(breaker)));
+        let sessions = config
+            .sessions
+            .as_ref()
+            .map(|sessions| Arc::new(SessionStore::new(sessions)));
+        if let Some(proxy) = &config.proxy {
+            info!(
+                "Sending upstream requests through proxy {}",
+                redact_proxy(proxy)
+            );
+        }
+        Yuanbao {
+            accounts: Arc::new(Self::make_accounts(&config)),
+            client: Self::client_builder(&config).build().unwrap(),
+            config,
+            quota,
+            rate_limiter,
+            breaker,
+            sessions,
+            credential_check: Arc::default(),
+        }
+    }
+
+    // 使用新的配置和凭证重建账号，配额计数和速率限制保持不变
+    pub fn reload(&self, config: Config) -> Yuanbao {
+        Yuanbao {
+            accounts: Arc::new(Self::make_accounts(&config)),
+            client: Self::client_builder(&config).build().unwrap(),
+            config,
+            quota: self.quota.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            breaker: self.breaker.clone(),
+            sessions: self.sessions.clone(),
+            // 凭证可能已经更换，重新自检
+            credential_check: Arc::default(),
+        }
+    }
+
+    // 每个账号一个客户端，Cookie 写在客户端的默认头部中
+    fn make_accounts(config: &Config) -> AccountPool {
+        let account_configs = config.account_configs();
+        let accounts = account_configs
+            .iter()
+            .enumerate()
+            .map(|(index, account)| {
+                let client = Self::client_builder(config)
+                    .default_headers(Self::make_headers(account, config))
+                    .build()
+                    .unwrap();
+                let name = account_name(account, index, account_configs.len());
+                Arc::new(Account::new(
+                    name,
+                    client,
+                    Duration::from_secs(config.account_cooldown_secs),
+                ))
+            })
+            .collect();
+        AccountPool::new(accounts)
+    }
+
+    // 按配置创建客户端，设置了代理时所有请求都经过代理；
+    // 保留空闲连接并开启 TCP keepalive，突发请求时可以复用连接，减少 TLS 握手
+    fn client_builder(config: &Config) -> reqwest::ClientBuilder {
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(seconds(config.pool_idle_timeout_secs))
+            .tcp_keepalive(seconds(config.tcp_keepalive_secs));
+        if let Some(proxy) = &config.proxy {
+            // 代理地址在加载配置时已经校验过
+            builder = builder.proxy(reqwest::Proxy::all(proxy).unwrap());
+        }
+        builder
+    }
+
+    // 获取各账号当日的配额使用情况和健康状态
+    pub fn account_status(&self) -> Vec<(String, QuotaUsage, bool)> {
+        self.accounts
+            .accounts()
+            .iter()
+            .map(|account| {
+                (
+                    account.name.clone(),
+                    self.quota.usage(&account.name),
+                    account.is_healthy(),
+                )
+            })
+            .collect()
+    }
+
+    // 自检所有账号的凭证，至少一个账号可用即通过；CREDENTIAL_CHECK_TTL 内直接返回上次的结果
+    pub async fn check_credentials(&self) -> anyhow::Result<()> {
+        if let Some((checked_at, result)) = &*self.credential_check.lock().unwrap()
+            && checked_at.elapsed() < CREDENTIAL_CHECK_TTL
+        {
+            return result.clone().map_err(Error::msg);
+        }
+        let result = self.check_accounts().await;
+        *self.credential_check.lock().unwrap() = Some((
+            Instant::now(),
+            result.as_ref().map_err(|err| format!("{:#}", err)).copied(),
+        ));
+        result
+    }
+
+    async fn check_accounts(&self) -> anyhow::Result<()> {
+        let mut passed = false;
+        let mut last_error = None;
+        for account in self.accounts.accounts() {
+            match self.check_account(account).await {
+                Ok(()) => passed = true,
+                Err(err) => {
+                    warn!(
+                        "Account '{}' failed the self-check: {:#}",
+                        account.name, err
+                    );
+                    last_error = Some(err);
+                }
+            }
+        }
+        if passed {
+            return Ok(());
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no account configured")))
+    }
+
+    // 自检凭证：调用一次创建对话接口，确认上游接受账号的 Cookie，随后删除创建的对话，
+    // 避免探针在账号中留下越来越多的对话
+    async fn check_account(&self, account: &Account) -> anyhow::Result<()> {
+        let response = account
+            .client
+            .post(self.conversation_create_url())
+            .headers(self.make_agent_headers(&self.config.agent_id))
+            .json(&json!({"agentId": self.config.agent_id}))
+            .send()
+            .await
+            .context("cannot reach yuanbao")?;
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            account.mark_failed();
+            return Err(CredentialsExpired(status.to_string()).into());
+        }
+        if !status.is_success() {
+            bail!("credential check failed with status {}", status);
+        }
+        account.mark_healthy();
+        let value: serde_json::Value = response.json().await.unwrap_or_default();
+        if let Some(conversation_id) = value["id"].as_str().filter(|id| !id.is_empty())
+            && let Err(err) = self
+                .delete_conversation(account, &self.config.agent_id, conversation_id)
+                .await
+        {
+            warn!(
+                "Cannot delete the self-check conversation {}: {:#}",
+                conversation_id, err
+            );
+        }
+        Ok(())
+    }
 
-impl ChatModel {
-    // 转换为 Yuanbao API 需要的字符串格式
-    pub fn as_yuanbao_string(&self) -> String {
-        match self {
-            ChatModel::DeepSeekV3 => "deep_seek_v3",
-            ChatModel::DeepSeekR1 => "deep_seek",
-        }
-        .to_string()
+    fn conversation_create_url(&self) -> String {
+        format!(
+            "{}{}",
+            self.config.upstream_base(),
+            self.config.conversation_create_path
+        )
     }
 
-    // 转换为常见的模型字符串格式
-    pub fn as_common_string(&self) -> String {
-        match self {
-            ChatModel::DeepSeekV3 => "deepseek-v3",
-            ChatModel::DeepSeekR1 => "deepseek-r1",
+    // 获取本次请求使用的 conversation_id：开启自动创建时新建一个对话，失败时回退到配置的固定 ID
+    async fn conversation_id(&self, account: &Account, agent_id: &str) -> anyhow::Result<String> {
+        if self.config.auto_create_conversation {
+            match self.create_conversation(account, agent_id).await {
+                Ok(id) => {
+                    info!("Created conversation: {}", id);
+                    return Ok(id);
+                }
+                Err(err) if self.config.conversation_id.is_empty() => return Err(err),
+                Err(err) => warn!(
+                    "Cannot create conversation, falling back to the fixed one: {:#}",
+                    err
+                ),
+            }
         }
-        .to_string()
+        info!(
+            "Using fixed conversation ID: {}",
+            self.config.conversation_id
+        );
+        Ok(self.config.conversation_id.clone())
     }
-}
-
-// 配置结构体
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub key: String,
-    pub agent_id: String,
-    pub hy_user: String,
-    pub hy_token: String,
-    pub port: u16,
-    pub conversation_id: String,  // 使用字符串来存储 UUID
-}
 
-// Yuanbao 结构体，用于与 API 交互
-#[derive(Clone)]
-pub struct Yuanbao {
-    config: Config,
-    client: Client,
-}
+    // 在上游创建一个新的对话，返回它的 ID
+    async fn create_conversation(
+        &self,
+        account: &Account,
+        agent_id: &str,
+    ) -> anyhow::Result<String> {
+        let response = account
+            .client
+            .post(self.conversation_create_url())
+            .headers(self.make_agent_headers(agent_id))
+            .json(&json!({"agentId": agent_id}))
+            .send()
+            .await
+            .context("cannot reach yuanbao")?
+            .error_for_status()?;
+        let value: serde_json::Value = response
+            .json()
+            .await
+            .context("invalid conversation response")?;
+        value["id"]
+            .as_str()
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .context("conversation response has no id")
+    }
 
-impl Yuanbao {
-    // 创建一个新的 Yuanbao 实例
-    pub fn new(config: Config) -> Yuanbao {
-        let headers = Self::make_headers(&config);
-        let client = reqwest::Client::builder()
-            .default_headers(headers)
-            .build()
-            .unwrap();
-        Yuanbao { config, client }
+    // 删除一个对话
+    async fn delete_conversation(
+        &self,
+        account: &Account,
+        agent_id: &str,
+        conversation_id: &str,
+    ) -> anyhow::Result<()> {
+        account
+            .client
+            .post(format!(
+                "{}{}",
+                self.config.upstream_base(),
+                self.config.conversation_delete_path
+            ))
+            .headers(self.make_agent_headers(agent_id))
+            .json(&json!({"conversationIds": [conversation_id]}))
+            .send()
+            .await
+            .context("cannot reach yuanbao")?
+            .error_for_status()?;
+        Ok(())
     }
 
-    // 创建一个新的对话，返回固定的 conversation_id
-    pub async fn create_conversation(&self) -> anyhow::Result<String> {
-        // 使用配置文件中的固定对话 ID
-        Ok(self.config.conversation_id.clone())  // 返回 UUID 字符串
+    // 熔断器的当前状态，未配置时为 None
+    pub fn circuit_breaker_state(&self) -> Option<&'static str> {
+        self.breaker.as_ref().map(|breaker| breaker.state())
     }
 
     // 创建聊天完成请求
     pub async fn create_completion(
         &self,
         request: ChatCompletionRequest,
-    ) -> anyhow::Result<Receiver<ChatCompletionEvent>> {
-        info!("Using fixed conversation");
+    ) -> anyhow::Result<Completion> {
+        // 上游连续失败时直接拒绝，不再等待连接超时
+        if let Some(breaker) = &self.breaker {
+            breaker.try_acquire()?;
+        }
+        let agent_id = self.agent_id(&request.chat_model);
+        let pinned = match &request.account {
+            Some(name) => Some(
+                self.accounts
+                    .accounts()
+                    .iter()
+                    .find(|account| account.name == *name)
+                    .cloned()
+                    .ok_or_else(|| UnknownAccount(name.clone()))?,
+            ),
+            None => None,
+        };
+        // 会话已经有对应的对话时，使用创建该对话的账号接着对话；指定了其他账号时新建对话
+        let session = match (&self.sessions, &request.session) {
+            (Some(sessions), Some(key)) => sessions.get(key).filter(|session| {
+                session.agent_id == agent_id
+                    && pinned
+                        .as_ref()
+                        .is_none_or(|account| account.name == session.account)
+            }),
+            _ => None,
+        };
+        let resumed = session.as_ref().and_then(|session| {
+            self.accounts.pick(|account| {
+                account.name == session.account && self.quota.has_remaining(&account.name)
+            })
+        });
+        if session.is_some()
+            && resumed.is_none()
+            && let (Some(sessions), Some(key)) = (&self.sessions, &request.session)
+        {
+            info!(
+                "Account of session '{}' is unavailable, starting a new conversation",
+                key
+            );
+            sessions.remove(key);
+        }
+        // 否则使用指定的账号，或者轮询选择一个当日配额未用完的账号；
+        // 指定的账号处于冷却中也照常使用
+        let Some(account) = resumed.clone().or_else(|| match &pinned {
+            Some(account) => self
+                .quota
+                .has_remaining(&account.name)
+                .then(|| account.clone()),
+            None => self
+                .accounts
+                .pick(|account| self.quota.has_remaining(&account.name)),
+        }) else {
+            let names: Vec<&str> = match &pinned {
+                Some(account) => vec![account.name.as_str()],
+                None => self
+                    .accounts
+                    .accounts()
+                    .iter()
+                    .map(|account| account.name.as_str())
+                    .collect(),
+            };
+            return Err(QuotaExceeded(names.join(", ")).into());
+        };
+        self.admit(&account).await?;
+        info!("Using account '{}'", account.name);
+        let (conversation_id, messages) = match session.filter(|_| resumed.is_some()) {
+            // 上游对话已经有之前的上下文，只发送新的消息
+            Some(session) => {
+                info!("Continuing conversation {}", session.conversation_id);
+                (
+                    session.conversation_id,
+                    request.messages.after_last_assistant(),
+                )
+            }
+            None => {
+                let conversation_id = self
+                    .start_conversation(&account, agent_id, request.session.as_ref())
+                    .await?;
+                (conversation_id, request.messages.clone())
+            }
+        };
+        let upstream = self
+            .build_upstream_request(&account, agent_id, conversation_id, &messages, &request)
+            .await?;
+
+        // 有界通道：客户端读得慢时上游事件的处理也会暂停，不会在内存里堆积整个回答
+        let (sender, receiver) =
+            bounded::<ChatCompletionEvent>(self.config.stream_buffer_size.max(1));
+        let headers = self.make_agent_headers(agent_id);
+        let mut ctx = StreamContext {
+            sender,
+            log_prompt_mode: self.config.log_prompt_mode,
+            account: account.clone(),
+            emitted: String::new(),
+            max_tokens: request
+                .max_tokens
+                .or(self
+                    .model_config(&request.chat_model)
+                    .and_then(|model| model.max_tokens))
+                .map(u64::from),
+            completion_tokens: 0,
+            stop: request
+                .stop
+                .iter()
+                .filter(|seq| !seq.is_empty())
+                .cloned()
+                .collect(),
+            pending: String::new(),
+            text_mode: self.config.text_stream_mode,
+            upstream_text: String::new(),
+            prompt_template: self.config.prompt_template.clone(),
+            output_started: false,
+            max_retries: self.config.upstream_retries,
+            retry_backoff: Duration::from_millis(self.config.upstream_retry_backoff_ms),
+            max_empty_retries: self.config.empty_retries,
+            empty_output: self.config.empty_output,
+            empty_placeholder: self.config.empty_output_placeholder.clone(),
+            deadline: (self.config.upstream_timeout_secs > 0)
+                .then(|| Instant::now() + Duration::from_secs(self.config.upstream_timeout_secs)),
+            idle_timeout: (self.config.upstream_idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(self.config.upstream_idle_timeout_secs)),
+        };
+        let this = self.clone();
+        let mut cleanup = ConversationCleanup::new(self, agent_id, &request);
+        let agent_id = agent_id.to_string();
+        let cancel = CancellationToken::new();
+        let guard = cancel.clone().drop_guard();
+        let handle = cancel.clone();
+        tokio::spawn(async move {
+            // 先把续写的开头发给客户端，使其拿到完整的回答
+            if let Some(prefill) = &request.prefill {
+                let _ = ctx
+                    .sender
+                    .send(ChatCompletionEvent::Message(ChatCompletionMessage {
+                        r#type: ChatCompletionMessageType::Msg,
+                        text: prefill.clone(),
+                    }))
+                    .await;
+            }
+            let stream_started_at = Instant::now();
+            let mut upstream = upstream;
+            let mut tried = Vec::new();
+            // 客户端断开后丢弃 SSE 连接，不再消耗上游
+            let result = loop {
+                cleanup.track(&ctx.account, &upstream.conversation_id);
+                let client = ctx.account.client.clone();
+                let result = select! {
+                    result = Self::stream_completion(client, upstream.url.clone(), headers.clone(), upstream.body.clone(), &mut ctx) => result,
+                    _ = cancel.cancelled() => {
+                        ctx.cancelled().await;
+                        return;
+                    }
+                };
+                if ctx.sender.is_closed() {
+                    break result;
+                }
+                METRICS.record_account_result(&ctx.account.name, result.is_ok());
+                // 只有上游不可用才算熔断器的失败，凭证失效等错误说明上游仍然可达
+                if let Some(breaker) = &this.breaker {
+                    match &result {
+                        Ok(_) => breaker.record(true),
+                        Err(err) if err.is::<UpstreamUnavailable>() => breaker.record(false),
+                        Err(_) => {}
+                    }
+                }
+                // 还没有输出内容时，账号被拒绝或上游一直不可用就换一个账号重新请求；
+                // 客户端指定了账号时不换
+                let Err(err) = &result else {
+                    break result;
+                };
+                if ctx.output_started || request.account.is_some() || !is_failover_error(err) {
+                    break result;
+                }
+                tried.push(ctx.account.name.clone());
+                let next = select! {
+                    next = this.failover(&tried, &agent_id, &request) => next,
+                    _ = cancel.cancelled() => {
+                        ctx.cancelled().await;
+                        return;
+                    }
+                };
+                match next {
+                    Ok(Some((account, next))) => {
+                        warn!(
+                            "Account '{}' failed before any output, retrying on account '{}': {:#}",
+                            ctx.account.name, account.name, err
+                        );
+                        ctx.account = account;
+                        upstream = next;
+                    }
+                    Ok(None) => break result,
+                    Err(next_err) => {
+                        warn!("Cannot fail over to another account: {:#}", next_err);
+                        break result;
+                    }
+                }
+            };
+            METRICS.stream_duration.observe(stream_started_at.elapsed());
+            if ctx.sender.is_closed() {
+                info!("Client disconnected, closing the upstream stream");
+            } else if let Err(err) = result {
+                warn!("SSE exit: {:#}", err);
+                METRICS.upstream_errors.fetch_add(1, Ordering::Relaxed);
+                let _ = ctx.sender.send(ChatCompletionEvent::Error(err)).await;
+            }
+        }.in_current_span());
 
-        // 获取固定的 conversation_id
+        Ok(Completion {
+            account: account.name.clone(),
+            receiver,
+            guard,
+            cancel: handle,
+        })
+    }
+
+    // 为请求新建对话，开启会话映射时记录会话对应的账号和对话
+    async fn start_conversation(
+        &self,
+        account: &Account,
+        agent_id: &str,
+        session: Option<&String>,
+    ) -> anyhow::Result<String> {
         let conversation_id = self
-            .create_conversation()
+            .conversation_id(account, agent_id)
             .await
             .context("cannot get conversation ID")?;
+        // 只记录新建的对话，回退到固定对话时不记录
+        if let (Some(sessions), Some(key)) = (&self.sessions, session)
+            && conversation_id != self.config.conversation_id
+        {
+            sessions.insert(
+                key.clone(),
+                Session {
+                    account: account.name.clone(),
+                    agent_id: agent_id.to_string(),
+                    conversation_id: conversation_id.clone(),
+                },
+            );
+        }
+        Ok(conversation_id)
+    }
 
-        info!("Using fixed conversation ID: {}", conversation_id);
+    // 生成发往上游的请求地址和请求体，消息中的图片会先上传
+    async fn build_upstream_request(
+        &self,
+        account: &Account,
+        agent_id: &str,
+        conversation_id: String,
+        messages: &ChatMessages,
+        request: &ChatCompletionRequest,
+    ) -> anyhow::Result<UpstreamRequest> {
+        // 消息中的图片先上传到元宝，再作为 multimedia 随请求发送
+        let upload_info_url = format!("{}/api/resource/genUploadInfo", self.config.upstream_base());
+        let fetch = ImageFetch {
+            max_bytes: self.config.image_max_bytes,
+            timeout: Duration::from_secs(self.config.image_fetch_timeout_secs),
+            allow_private: self.config.image_allow_private_urls,
+        };
+        let mut multimedia = Vec::new();
+        for image in messages.0.iter().flat_map(|item| &item.images) {
+            let item = upload_image(
+                &account.client,
+                &self.client,
+                &upload_info_url,
+                &fetch,
+                image,
+            )
+            .await
+            .context("cannot upload image")?;
+            multimedia.push(item);
+        }
+        if !multimedia.is_empty() {
+            info!("Uploaded {} images", multimedia.len());
+        }
+        let body = self.build_body(agent_id, messages, request, multimedia)?;
+        let formatted_url = format!(
+            "{}/api/chat/{}",
+            self.config.upstream_base(),
+            conversation_id
+        );
+        METRICS.record_upstream_request();
+        Ok(UpstreamRequest {
+            conversation_id,
+            url: formatted_url,
+            body,
+        })
+    }
 
-        let prompt = request.messages.to_string();
-        let body = json!({
+    // 生成发往上游的请求体，multimedia 是已经上传的图片
+    fn build_body(
+        &self,
+        agent_id: &str,
+        messages: &ChatMessages,
+        request: &ChatCompletionRequest,
+        multimedia: Vec<serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut prompt =
+            messages.render(self.config.replay_reasoning, &self.config.prompt_template)?;
+        // 追加的指令和续写的回答使用与其他消息相同的模板
+        let template = &self.config.prompt_template;
+        if request.json_mode {
+            prompt = append_turns(&prompt, &render_turn(template, "system", JSON_MODE_PROMPT));
+        }
+        if let Some(prefill) = &request.prefill {
+            let turns = render_turn(template, "system", PREFILL_PROMPT)
+                + &render_open_turn(template, "assistant", prefill);
+            prompt = append_turns(&prompt, &turns);
+        }
+        debug!("Prompt: {}", self.config.log_prompt_mode.display(&prompt));
+        let mut body = json!({
             "model": "gpt_175B_0404",
             "prompt": prompt,
-            "plugin": "Adaptive",
+            "plugin": self.plugin(request),
             "displayPrompt": prompt,
             "displayPromptType": 1,
-            "options": {"imageIntention": {"needIntentionModel": true, "backendUpdateFlag": 2, "intentionStatus": true}},
-            "multimedia": [],
-            "agentId": self.config.agent_id,
-            "supportHint": 1,
-            "version": "v2",
-            "chatModelId": request.chat_model.as_yuanbao_string(),
+            "options": self.config.yuanbao_options,
+            "multimedia": multimedia,
+            "agentId": agent_id,
+            "supportHint": self.config.yuanbao_support_hint,
+            "version": self.config.yuanbao_version,
+            "chatModelId": request.chat_model.chat_model_id,
         });
+        // 客户端传入的参数优先，未传入的使用模型的默认值
+        request
+            .sampling
+            .or(self.model_sampling(&request.chat_model))
+            .apply(&mut body);
+        Ok(body)
+    }
 
-        let formatted_url = format!("https://yuanbao.tencent.com/api/chat/{}", conversation_id);
+    // 不请求上游，返回 create_completion 会发送的请求体，用于调试；图片不上传，返回其数量
+    pub fn preview_body(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> anyhow::Result<serde_json::Value> {
+        let images = request
+            .messages
+            .0
+            .iter()
+            .map(|item| item.images.len())
+            .sum::<usize>();
+        let body = self.build_body(
+            self.agent_id(&request.chat_model),
+            &request.messages,
+            request,
+            Vec::new(),
+        )?;
+        Ok(json!({
+            "url": format!("{}/api/chat/{{conversation_id}}", self.config.upstream_base()),
+            "body": body,
+            "images": images,
+        }))
+    }
 
-        let mut sse = EventSource::new(self.client.post(&formatted_url).json(&body))
-            .context("failed to get next event")?;
+    // 按上游速率限制等待，放行后再为账号记一次配额，被限流的请求不占用配额；
+    // 等待期间配额被其他请求用完时返回 QuotaExceeded
+    async fn admit(&self, account: &Account) -> anyhow::Result<()> {
+        if let Some(rate_limiter) = &self.rate_limiter
+            && let Err(err) = rate_limiter.acquire(&account.name).await
+        {
+            METRICS
+                .upstream_rate_limited
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(err.into());
+        }
+        if !self.quota.try_acquire(&account.name) {
+            return Err(QuotaExceeded(account.name.clone()).into());
+        }
+        Ok(())
+    }
 
-        let (sender, receiver) = unbounded::<ChatCompletionEvent>();
-        tokio::spawn(async move {
-            if let Err(err) = Self::process_sse(&mut sse, sender).await {
-                warn!("SSE exit: {:#}", err);
-            }
-        });
+    // 换一个没有试过的账号：新建对话并发送完整的消息，没有可用的账号时返回 None
+    async fn failover(
+        &self,
+        tried: &[String],
+        agent_id: &str,
+        request: &ChatCompletionRequest,
+    ) -> anyhow::Result<Option<(Arc<Account>, UpstreamRequest)>> {
+        let Some(account) = self.accounts.pick(|account| {
+            !tried.contains(&account.name) && self.quota.has_remaining(&account.name)
+        }) else {
+            return Ok(None);
+        };
+        self.admit(&account).await?;
+        let conversation_id = self
+            .start_conversation(&account, agent_id, request.session.as_ref())
+            .await?;
+        let upstream = self
+            .build_upstream_request(
+                &account,
+                agent_id,
+                conversation_id,
+                &request.messages,
+                request,
+            )
+            .await?;
+        Ok(Some((account, upstream)))
+    }
 
-        Ok(receiver)
+    // 发起请求并转发 SSE 事件，上游中途断开时带上续写提示重新连接
+    #[instrument(skip_all, fields(account = ctx.account.name.as_str()))]
+    async fn stream_completion(
+        client: Client,
+        url: String,
+        headers: HeaderMap,
+        mut body: serde_json::Value,
+        ctx: &mut StreamContext,
+    ) -> anyhow::Result<()> {
+        let prompt = body["prompt"].as_str().unwrap_or("").to_string();
+        let mut reconnects = 0;
+        let mut retries = 0;
+        let mut empty_retries = 0;
+        loop {
+            let mut sse = EventSource::new(client.post(&url).headers(headers.clone()).json(&body))
+                .context("failed to get next event")?;
+            // 重新连接后上游从头发送正文
+            ctx.upstream_text.clear();
+            let exit = Self::process_sse(&mut sse, ctx).await?;
+            ctx.flush_text().await?;
+            match exit {
+                // 没有向客户端输出过任何内容（包括推理内容）时才重试，避免重复输出；超时不重试
+                SseExit::Finish(finish_reason)
+                    if !ctx.output_started
+                        && finish_reason != TIMEOUT_FINISH_REASON
+                        && empty_retries < ctx.max_empty_retries =>
+                {
+                    sse.close();
+                    empty_retries += 1;
+                    warn!(
+                        "Upstream returned an empty completion, retrying ({}/{})",
+                        empty_retries, ctx.max_empty_retries
+                    );
+                    sleep(jitter(ctx.retry_backoff)).await;
+                }
+                SseExit::Finish(finish_reason) => {
+                    if ctx.emitted.is_empty() {
+                        match ctx.empty_output {
+                            EmptyOutput::Keep => {}
+                            EmptyOutput::Placeholder => {
+                                warn!("Upstream returned no text, sending the placeholder");
+                                let placeholder = ctx.empty_placeholder.clone();
+                                ctx.emit(placeholder).await?;
+                            }
+                            EmptyOutput::Error => return Err(EmptyCompletion.into()),
+                        }
+                    }
+                    ctx.sender
+                        .send(ChatCompletionEvent::Finish(finish_reason))
+                        .await?;
+                    return Ok(());
+                }
+                SseExit::Reconnect if reconnects < MAX_RECONNECTS => {
+                    sse.close();
+                    reconnects += 1;
+                    warn!(
+                        "Upstream dropped the stream, reconnecting ({reconnects}/{MAX_RECONNECTS})"
+                    );
+                    let continuation =
+                        reconnect_prompt(&prompt, &ctx.prompt_template, &ctx.emitted);
+                    body["prompt"] = json!(continuation);
+                    body["displayPrompt"] = json!(continuation);
+                }
+                SseExit::Reconnect => bail!("upstream dropped the stream too many times"),
+                SseExit::Retry(err) if retries < ctx.max_retries => {
+                    sse.close();
+                    // 指数退避：每次重试的等待时间翻倍，再加上随机抖动
+                    let backoff = jitter(
+                        ctx.retry_backoff
+                            .saturating_mul(1 << retries.min(16))
+                            .min(MAX_RETRY_BACKOFF),
+                    );
+                    retries += 1;
+                    warn!(
+                        "Upstream is temporarily unavailable, retrying in {:?} ({}/{}): {:#}",
+                        backoff, retries, ctx.max_retries, err
+                    );
+                    sleep(backoff).await;
+                }
+                SseExit::Retry(err) => return Err(err.context(UpstreamUnavailable)),
+            }
+        }
     }
 
     // 处理 SSE 事件流
     async fn process_sse(
         sse: &mut EventSource,
-        sender: Sender<ChatCompletionEvent>,
-    ) -> anyhow::Result<()> {
+        ctx: &mut StreamContext,
+    ) -> anyhow::Result<SseExit> {
         let mut finish_reason = "stop".to_string();
+        let mut fragments = FragmentBuffer::default();
         loop {
             let event;
             select! {
-                Some(e)=sse.next()=>{
-                    event=e;
+                e = sse.next() => match e {
+                    Some(e) => event = e,
+                    None => {
+                        info!("Stream ended (pattern else)");
+                        break;
+                    }
                 },
-                else => {
-                    info!("Stream ended (pattern else)");
-                    break;
+                _ = sleep_until(ctx.deadline.unwrap_or_else(Instant::now).into()), if ctx.deadline.is_some() => {
+                    warn!("Upstream did not finish within the overall timeout");
+                    return Ok(SseExit::Finish(TIMEOUT_FINISH_REASON.to_string()));
+                }
+                _ = sleep(ctx.idle_timeout.unwrap_or_default()), if ctx.idle_timeout.is_some() => {
+                    warn!("Upstream sent nothing within the idle timeout");
+                    return Ok(SseExit::Finish(TIMEOUT_FINISH_REASON.to_string()));
                 }
             }
             match event {
-                Ok(Event::Open) => {}
+                Ok(Event::Open) => {
+                    ctx.account.mark_healthy();
+                }
                 Ok(Event::Message(message)) => {
+                    if message.event == "error" {
+                        return Err(UpstreamError(upstream_error_message(&message.data)).into());
+                    }
                     if message.event != "message" {
                         continue;
                     }
-                    let res = serde_json::from_str::<serde_json::Value>(&message.data);
-                    let value = match res {
-                        Ok(v) => v,
-                        Err(_) => continue,
+                    // 一个 JSON 被拆在多个事件中时先缓存，拼接完整后再处理
+                    let Some(value) = fragments.parse(&message.data) else {
+                        continue;
                     };
                     match value["type"].as_str().unwrap_or("") {
+                        // 不能当作正常结束，否则客户端只会收到一个空的回答
+                        "error" => {
+                            return Err(
+                                UpstreamError(upstream_error_message(&value.to_string())).into()
+                            );
+                        }
                         "think" => {
                             let content = value["content"].as_str().unwrap_or("");
                             if content.is_empty() {
                                 continue;
                             }
-                            sender
+                            METRICS
+                                .tokens_emitted
+                                .fetch_add(estimate_tokens(content), Ordering::Relaxed);
+                            ctx.output_started = true;
+                            // 先发出为匹配停止序列暂存的正文，保持与上游相同的先后顺序
+                            ctx.flush_text().await?;
+                            ctx.sender
                                 .send(ChatCompletionEvent::Message(ChatCompletionMessage {
                                     r#type: ChatCompletionMessageType::Think,
                                     text: content.to_string(),
@@ -236,75 +2026,719 @@ impl Yuanbao {
                                 .await?;
                         }
                         "text" => {
-                            let msg = value["msg"].as_str().unwrap_or("");
-                            sender
-                                .send(ChatCompletionEvent::Message(ChatCompletionMessage {
-                                    r#type: ChatCompletionMessageType::Msg,
-                                    text: msg.to_string(),
-                                }))
-                                .await?;
+                            let msg = ctx.text_delta(value["msg"].as_str().unwrap_or(""));
+                            ctx.output_started |= !msg.is_empty();
+                            if let Some(reason) = ctx.send_text(&msg).await? {
+                                return Ok(SseExit::Finish(reason.to_string()));
+                            }
                         }
                         _ => {
                             let stop_reason = value["stopReason"].as_str().unwrap_or("");
                             if !stop_reason.is_empty() {
-                                finish_reason = stop_reason.to_string();
+                                finish_reason = map_finish_reason(stop_reason).to_string();
                             }
                         }
                     }
-                    debug!(?message, "Event message");
+                    debug!(
+                        event = message.event,
+                        data = ctx.log_prompt_mode.display(&message.data),
+                        "Event message"
+                    );
                 }
                 Err(err) => match err {
                     reqwest_eventsource::Error::StreamEnded => {
                         info!("Stream ended");
                         break;
                     }
+                    reqwest_eventsource::Error::Transport(ref e) if is_go_away(e) => {
+                        warn!("Upstream sent HTTP/2 GOAWAY: {}", err);
+                        METRICS.goaway_reconnects.fetch_add(1, Ordering::Relaxed);
+                        return Ok(SseExit::Reconnect);
+                    }
+                    // 凭证失效时上游会返回 200 的 HTML 登录页而不是事件流
+                    reqwest_eventsource::Error::InvalidContentType(content_type, _) => {
+                        ctx.account.mark_failed();
+                        let content_type = content_type.to_str().unwrap_or("").to_string();
+                        return Err(CredentialsExpired(content_type).into());
+                    }
+                    reqwest_eventsource::Error::InvalidStatusCode(status, _)
+                        if status == StatusCode::UNAUTHORIZED
+                            || status == StatusCode::FORBIDDEN =>
+                    {
+                        ctx.account.mark_failed();
+                        return Err(CredentialsExpired(status.to_string()).into());
+                    }
+                    reqwest_eventsource::Error::InvalidStatusCode(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        _,
+                    ) if !ctx.output_started => {
+                        ctx.account.mark_rate_limited();
+                        return Ok(SseExit::Retry(UpstreamRateLimited.into()));
+                    }
+                    // 还没有输出任何内容时，连接失败和上游临时不可用可以直接重试
+                    reqwest_eventsource::Error::Transport(_)
+                    | reqwest_eventsource::Error::InvalidStatusCode(
+                        StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT,
+                        _,
+                    ) if !ctx.output_started => {
+                        return Ok(SseExit::Retry(anyhow!("stream error {}", err)));
+                    }
                     _ => {
                         return Err(anyhow!("stream error {}", err));
                     }
                 },
             }
         }
-        sender
-            .send(ChatCompletionEvent::Finish(finish_reason))
-            .await?;
-        Ok(())
+        Ok(SseExit::Finish(finish_reason))
+    }
+
+    // 获取模型使用的 agent_id，模型未单独配置时使用账号默认的
+    fn agent_id(&self, chat_model: &ResolvedModel) -> &str {
+        self.model_config(chat_model)
+            .and_then(|model| model.agent_id.as_deref())
+            .unwrap_or(&self.config.agent_id)
+    }
+
+    // 模型的单独配置
+    fn model_config(&self, chat_model: &ResolvedModel) -> Option<&ModelConfig> {
+        self.config.models.get(&chat_model.name)
     }
 
-    // 创建 HTTP 请求的头部
-    fn make_headers(config: &Config) -> HeaderMap {
+    // 获取模型配置的默认采样参数
+    fn model_sampling(&self, chat_model: &ResolvedModel) -> SamplingParams {
+        self.model_config(chat_model)
+            .map(|model| model.sampling)
+            .unwrap_or_default()
+    }
+
+    // 请求使用的插件：客户端指定的优先，其次是模型的默认值，最后是全局配置
+    fn plugin<'a>(&'a self, request: &'a ChatCompletionRequest) -> &'a str {
+        request
+            .plugin
+            .as_deref()
+            .or_else(|| {
+                self.model_config(&request.chat_model)
+                    .and_then(|model| model.plugin.as_deref())
+            })
+            .unwrap_or(&self.config.plugin)
+    }
+
+    // 创建与 agent 相关的请求头部，不同模型可能使用不同的 agent
+    fn make_agent_headers(&self, agent_id: &str) -> HeaderMap {
+        let referer = format!("{}/chat/{}", self.config.upstream_base(), agent_id);
         HeaderMap::from_iter(vec![
             (
-                HeaderName::from_str("Cookie").unwrap(),
-                HeaderValue::from_str(&format!(
-                    "hy_source=web; hy_user={}; hy_token={}",
-                    config.hy_user, config.hy_token
-                ))
-                .unwrap(),
+                HeaderName::from_str("Referer").unwrap(),
+                HeaderValue::from_str(&referer).unwrap(),
             ),
             (
-                HeaderName::from_str("Origin").unwrap(),
-                HeaderValue::from_str("https://yuanbao.tencent.com").unwrap(),
+                HeaderName::from_str("X-Agentid").unwrap(),
+                HeaderValue::from_str(agent_id).unwrap(),
             ),
+        ])
+    }
+
+    // 创建 HTTP 请求的公共头部，Origin 取自上游地址
+    fn make_headers(account: &AccountConfig, config: &Config) -> HeaderMap {
+        let user_agent = config.user_agent.as_deref();
+        HeaderMap::from_iter(vec![
             (
-                HeaderName::from_str("Referer").unwrap(),
+                HeaderName::from_str("Cookie").unwrap(),
                 HeaderValue::from_str(&format!(
-                    "https://yuanbao.tencent.com/chat/{}",
-                    config.agent_id
+                    "hy_source=web; hy_user={}; hy_token={}",
+                    account.hy_user, account.hy_token
                 ))
                 .unwrap(),
             ),
             (
-                HeaderName::from_str("X-Agentid").unwrap(),
-                HeaderValue::from_str(&config.agent_id).unwrap(),
+                HeaderName::from_str("Origin").unwrap(),
+                HeaderValue::from_str(&config.upstream_origin()).unwrap(),
             ),
             (
                 HeaderName::from_str("User-Agent").unwrap(),
-                HeaderValue::from_str(
-                    "Mozilla/5.0 (Windows NT 10.0; Win64; x64)\
-                     AppleWebKit/537.36 (KHTML, like Gecko) Chrome/134.0.0.0 Safari/537.36",
-                )
-                .unwrap(),
+                HeaderValue::from_str(user_agent.unwrap_or(DEFAULT_USER_AGENT)).unwrap(),
             ),
         ])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::post;
+    use std::future::IntoFuture;
+    use tokio::net::TcpListener;
+
+    // 在本机随机端口上启动桩服务，返回它的地址
+    async fn serve(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, router).into_future());
+        format!("http://{}", addr)
+    }
+
+    // 请求 base_url 的配置，extra 中的配置项追加在后面
+    fn config(base_url: &str, extra: &str) -> Config {
+        format!(
+            "key: sk-test\nagent_id: agent\nhy_user: user\nhy_token: token\nport: 7555\nbase_url: {base_url}\n{extra}"
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            reasoning_content: None,
+            tool_calls: None,
+            images: Vec::new(),
+        }
+    }
+
+    // 使用 deepseek-v3 的请求，其他参数为默认值
+    fn request(messages: Vec<ChatMessage>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            messages: ChatMessages(messages),
+            chat_model: ChatModel::DeepSeekV3.into(),
+            session: None,
+            account: None,
+            prefill: None,
+            sampling: SamplingParams::default(),
+            max_tokens: None,
+            stop: Vec::new(),
+            json_mode: false,
+            plugin: None,
+        }
+    }
+
+    // 使用固定对话 c1、不新建对话的配置
+    const FIXED_CONVERSATION: &str = "auto_create_conversation: false\nconversation_id: c1";
+
+    // 读取补全的全部事件，直到通道关闭
+    async fn events(completion: Completion) -> Vec<ChatCompletionEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = completion.receiver.recv().await {
+            events.push(event);
+        }
+        events
+    }
+
+    // 对聊天请求返回固定 SSE 内容的桩服务
+    fn sse_stub(body: &'static str) -> Router {
+        Router::new().route(
+            "/api/chat/{id}",
+            post(move || async move {
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/event-stream")],
+                    body,
+                )
+            }),
+        )
+    }
+
+    // 请求桩服务并读取全部事件
+    async fn stub_events(router: Router) -> Vec<ChatCompletionEvent> {
+        let yuanbao = Yuanbao::new(config(&serve(router).await, FIXED_CONVERSATION));
+        let completion = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await
+            .unwrap();
+        events(completion).await
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_stream_with_stop() {
+        // 发出一段正文后不再结束的上游
+        let router = Router::new().route(
+            "/api/chat/{id}",
+            post(|| async {
+                let first = futures::stream::once(async {
+                    Ok::<_, std::io::Error>("data: {\"type\":\"text\",\"msg\":\"你好\"}\n\n")
+                });
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/event-stream")],
+                    axum::body::Body::from_stream(first.chain(futures::stream::pending())),
+                )
+            }),
+        );
+        let yuanbao = Yuanbao::new(config(&serve(router).await, FIXED_CONVERSATION));
+        let completion = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await
+            .unwrap();
+        let first = completion.receiver.recv().await.unwrap();
+        assert!(matches!(first, ChatCompletionEvent::Message(ref m) if m.text == "你好"));
+        completion.cancel.cancel();
+        let rest = tokio::time::timeout(Duration::from_secs(5), events(completion))
+            .await
+            .unwrap();
+        assert!(matches!(rest[..], [ChatCompletionEvent::Finish(ref reason)] if reason == "stop"));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_do_not_use_quota() {
+        let base_url = serve(Router::new()).await;
+        let yuanbao = Yuanbao::new(config(
+            &base_url,
+            "daily_quota: 5\nupstream_rate_limit:\n  global: 0.01\n  policy: reject",
+        ));
+        let _ = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await;
+        let err = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<crate::ratelimit::RateLimited>());
+        assert_eq!(yuanbao.quota.usage("default").used, 1);
+    }
+
+    #[test]
+    fn accounts_can_override_the_daily_quota() {
+        let yuanbao = Yuanbao::new(config(
+            "http://127.0.0.1:1",
+            "daily_quota: 5\naccounts:\n  - name: a\n    hy_user: u\n    hy_token: t\n    daily_quota: 2\n  - hy_user: u\n    hy_token: t",
+        ));
+        let quotas: Vec<(String, Option<u64>)> = yuanbao
+            .account_status()
+            .into_iter()
+            .map(|(name, usage, _)| (name, usage.quota))
+            .collect();
+        assert_eq!(
+            quotas,
+            [
+                ("a".to_string(), Some(2)),
+                ("account-2".to_string(), Some(5))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn credential_check_deletes_its_conversation_and_is_cached() {
+        let created = Arc::new(Mutex::new(0));
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .route(
+                "/api/user/agent/conversation/create",
+                post({
+                    let created = created.clone();
+                    move || async move {
+                        *created.lock().unwrap() += 1;
+                        axum::Json(json!({"id": "probe-1"}))
+                    }
+                }),
+            )
+            .route(
+                "/api/user/agent/conversation/v1/clear",
+                post({
+                    let deleted = deleted.clone();
+                    move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                        deleted
+                            .lock()
+                            .unwrap()
+                            .push(body["conversationIds"].clone());
+                    }
+                }),
+            );
+        let yuanbao = Yuanbao::new(config(&serve(router).await, ""));
+        yuanbao.check_credentials().await.unwrap();
+        yuanbao.check_credentials().await.unwrap();
+        assert_eq!(*created.lock().unwrap(), 1);
+        assert_eq!(*deleted.lock().unwrap(), [json!(["probe-1"])]);
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_fail_the_check() {
+        let router = Router::new().route(
+            "/api/user/agent/conversation/create",
+            post(|| async { StatusCode::UNAUTHORIZED }),
+        );
+        let yuanbao = Yuanbao::new(config(&serve(router).await, ""));
+        assert!(yuanbao.check_credentials().await.is_err());
+        assert!(!yuanbao.account_status()[0].2);
+    }
+
+    #[test]
+    fn replay_reasoning_places_the_reasoning_of_assistant_turns() {
+        let mut answer = message("assistant", "42");
+        answer.reasoning_content = Some("6 * 7".to_string());
+        let messages = ChatMessages(vec![message("user", "question"), answer]);
+        let render = |mode| messages.render(mode, DEFAULT_PROMPT_TEMPLATE).unwrap();
+        assert_eq!(
+            render(ReplayReasoning::Prepend),
+            "#[user]\nquestion\n\n#[assistant]\n<think>\n6 * 7\n</think>\n\n42\n\n"
+        );
+        assert_eq!(
+            render(ReplayReasoning::Append),
+            "#[user]\nquestion\n\n#[assistant]\n42\n\n<think>\n6 * 7\n</think>\n\n"
+        );
+        assert_eq!(
+            render(ReplayReasoning::Drop),
+            "#[user]\nquestion\n\n#[assistant]\n42\n\n"
+        );
+    }
+
+    // 调用了 get_weather 工具的对话
+    fn tool_conversation() -> ChatMessages {
+        let mut call = message("assistant", "");
+        call.tool_calls = Some(vec![serde_json::json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "get_weather", "arguments": "{\"city\":\"北京\"}"},
+        })]);
+        ChatMessages(vec![
+            message("user", "北京天气怎么样"),
+            call,
+            message("tool", "晴，25 度"),
+            message("user", "需要带伞吗"),
+        ])
+    }
+
+    #[test]
+    fn dropped_tool_turns_leave_no_trace_in_the_prompt() {
+        let mut messages = tool_conversation();
+        messages.strip_tools(ToolMessages::Drop);
+        let prompt = messages
+            .render(ReplayReasoning::Drop, DEFAULT_PROMPT_TEMPLATE)
+            .unwrap();
+        assert_eq!(prompt, "#[user]\n北京天气怎么样\n\n#[user]\n需要带伞吗\n\n");
+        assert!(!prompt.contains("#[tool]"));
+        assert!(!prompt.contains("get_weather"));
+    }
+
+    #[test]
+    fn summarized_tool_turns_become_plain_text() {
+        let mut messages = tool_conversation();
+        messages.strip_tools(ToolMessages::Summarize);
+        let prompt = messages
+            .render(ReplayReasoning::Drop, DEFAULT_PROMPT_TEMPLATE)
+            .unwrap();
+        assert!(!prompt.contains("#[tool]"));
+        assert!(prompt.contains("#[assistant]\n（调用了工具：get_weather）"));
+        assert!(prompt.contains("#[user]\n（工具返回结果：晴，25 度）"));
+    }
+
+    #[tokio::test]
+    async fn html_login_page_means_expired_credentials() {
+        let router = Router::new().route(
+            "/api/chat/{id}",
+            post(|| async {
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/html; charset=utf-8")],
+                    "<html><body>请登录</body></html>",
+                )
+            }),
+        );
+        let yuanbao = Yuanbao::new(config(&serve(router).await, FIXED_CONVERSATION));
+        let completion = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await
+            .unwrap();
+        let events = events(completion).await;
+        match events.as_slice() {
+            [ChatCompletionEvent::Error(err)] => assert!(err.is::<CredentialsExpired>()),
+            events => panic!("unexpected events: {:?}", events),
+        }
+        assert!(!yuanbao.accounts.accounts()[0].is_healthy());
+    }
+
+    const MODEL_AGENTS: &str = "models:\n  deepseek-r1:\n    agent_id: agent-r1";
+
+    #[test]
+    fn models_can_use_their_own_agent_id() {
+        let yuanbao = Yuanbao::new(config("http://127.0.0.1:1", MODEL_AGENTS));
+        let v3 = request(vec![message("user", "hi")]);
+        let r1 = ChatCompletionRequest {
+            chat_model: ChatModel::DeepSeekR1.into(),
+            ..v3.clone()
+        };
+        for (request, agent_id) in [(&v3, "agent"), (&r1, "agent-r1")] {
+            let preview = yuanbao.preview_body(request).unwrap();
+            assert_eq!(preview["body"]["agentId"], agent_id);
+            let headers = yuanbao.make_agent_headers(yuanbao.agent_id(&request.chat_model));
+            assert_eq!(headers["X-Agentid"], agent_id);
+        }
+    }
+
+    #[test]
+    fn client_sampling_overrides_the_model_default() {
+        let yuanbao = Yuanbao::new(config(
+            "http://127.0.0.1:1",
+            "models:\n  deepseek-v3:\n    temperature: 0.7\n    top_p: 0.9",
+        ));
+        let omitted = request(vec![message("user", "hi")]);
+        let body = &yuanbao.preview_body(&omitted).unwrap()["body"];
+        assert_eq!(body["temperature"].as_f64().unwrap() as f32, 0.7);
+        assert_eq!(body["topP"].as_f64().unwrap() as f32, 0.9);
+        let provided = ChatCompletionRequest {
+            sampling: SamplingParams {
+                temperature: Some(0.2),
+                ..SamplingParams::default()
+            },
+            ..omitted
+        };
+        let body = &yuanbao.preview_body(&provided).unwrap()["body"];
+        assert_eq!(body["temperature"].as_f64().unwrap() as f32, 0.2);
+        assert_eq!(body["topP"].as_f64().unwrap() as f32, 0.9);
+    }
+
+    // 标记桩服务的响应流是否已经被丢弃（连接关闭）
+    struct DropFlag(Arc<std::sync::atomic::AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_the_completion_closes_the_upstream_stream() {
+        let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = dropped.clone();
+        let router = Router::new().route(
+            "/api/chat/{id}",
+            post(move || {
+                let guard = DropFlag(flag.clone());
+                // 每 10 毫秒发送一段正文，永不结束
+                let body = futures::stream::unfold(guard, |guard| async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    let chunk = "data: {\"type\":\"text\",\"msg\":\"x\"}\n\n";
+                    Some((Ok::<_, std::io::Error>(chunk), guard))
+                });
+                async move {
+                    (
+                        [(axum::http::header::CONTENT_TYPE, "text/event-stream")],
+                        axum::body::Body::from_stream(body),
+                    )
+                }
+            }),
+        );
+        let yuanbao = Yuanbao::new(config(&serve(router).await, FIXED_CONVERSATION));
+        let completion = yuanbao
+            .create_completion(request(vec![message("user", "hi")]))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            let event = completion.receiver.recv().await.unwrap();
+            assert!(matches!(event, ChatCompletionEvent::Message(_)));
+        }
+        assert!(!dropped.load(Ordering::SeqCst));
+        drop(completion);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !dropped.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("upstream stream was not closed after the client went away");
+    }
+
+    // 只用来转换正文的流上下文
+    fn stream_context(text_mode: TextStreamMode) -> StreamContext {
+        let (sender, _) = bounded(1);
+        StreamContext {
+            sender,
+            log_prompt_mode: LogPromptMode::default(),
+            account: Arc::new(Account::new("a".to_string(), Client::new(), Duration::ZERO)),
+            emitted: String::new(),
+            max_tokens: None,
+            completion_tokens: 0,
+            output_started: false,
+            max_retries: 0,
+            retry_backoff: Duration::ZERO,
+            max_empty_retries: 0,
+            empty_output: EmptyOutput::default(),
+            empty_placeholder: String::new(),
+            deadline: None,
+            idle_timeout: None,
+            stop: Vec::new(),
+            pending: String::new(),
+            text_mode,
+            upstream_text: String::new(),
+            prompt_template: DEFAULT_PROMPT_TEMPLATE.to_string(),
+        }
+    }
+
+    fn deltas(text_mode: TextStreamMode, msgs: &[&str]) -> Vec<String> {
+        let mut ctx = stream_context(text_mode);
+        msgs.iter().map(|msg| ctx.text_delta(msg)).collect()
+    }
+
+    #[test]
+    fn incremental_text_is_passed_through() {
+        let msgs = ["你", "好", "，世界"];
+        assert_eq!(deltas(TextStreamMode::Auto, &msgs), msgs);
+        assert_eq!(deltas(TextStreamMode::Incremental, &msgs), msgs);
+        // 增量模式下重复的片段也照常输出
+        assert_eq!(
+            deltas(TextStreamMode::Incremental, &["哈", "哈哈"]),
+            ["哈", "哈哈"]
+        );
+    }
+
+    #[test]
+    fn cumulative_text_emits_only_the_new_part() {
+        let msgs = ["你", "你好", "你好，世界"];
+        assert_eq!(deltas(TextStreamMode::Auto, &msgs), ["你", "好", "，世界"]);
+        assert_eq!(
+            deltas(TextStreamMode::Cumulative, &msgs),
+            ["你", "好", "，世界"]
+        );
+        // 累积的正文与之前的不一致时，视为重新开始
+        assert_eq!(
+            deltas(TextStreamMode::Cumulative, &["你好", "重来"]),
+            ["你好", "重来"]
+        );
+    }
+
+    // 事件是否为带有 message 的 UpstreamError，且在此之前只收到了“部分”这段正文
+    fn assert_upstream_error(events: &[ChatCompletionEvent], message: &str) {
+        match events {
+            [
+                ChatCompletionEvent::Message(text),
+                ChatCompletionEvent::Error(err),
+            ] => {
+                assert_eq!(text.text, "部分");
+                let err = err.downcast_ref::<UpstreamError>().unwrap();
+                assert_eq!(err.0, message);
+            }
+            events => panic!("unexpected events: {:?}", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn error_event_is_propagated() {
+        let events = stub_events(sse_stub(concat!(
+            "data: {\"type\":\"text\",\"msg\":\"部分\"}\n\n",
+            "event: error\ndata: {\"msg\":\"内容不合规\"}\n\n",
+            "data: {\"stopReason\":\"stop\"}\n\n",
+        )))
+        .await;
+        assert_upstream_error(&events, "内容不合规");
+    }
+
+    #[tokio::test]
+    async fn error_payload_is_propagated() {
+        let events = stub_events(sse_stub(concat!(
+            "data: {\"type\":\"text\",\"msg\":\"部分\"}\n\n",
+            "data: {\"type\":\"error\",\"message\":\"今日额度已用完\"}\n\n",
+        )))
+        .await;
+        assert_upstream_error(&events, "今日额度已用完");
+    }
+
+    #[tokio::test]
+    async fn interleaved_think_and_text_keep_their_order() {
+        let events = stub_events(sse_stub(concat!(
+            "data: {\"type\":\"think\",\"content\":\"想一\"}\n\n",
+            "data: {\"type\":\"think\",\"content\":\"想二\"}\n\n",
+            "data: {\"type\":\"text\",\"msg\":\"答一\"}\n\n",
+            "data: {\"type\":\"think\",\"content\":\"想三\"}\n\n",
+            "data: {\"type\":\"text\",\"msg\":\"答二\"}\n\n",
+        )))
+        .await;
+        let order: Vec<String> = events
+            .iter()
+            .map(|event| match event {
+                ChatCompletionEvent::Message(message) => match message.r#type {
+                    ChatCompletionMessageType::Think => format!("think:{}", message.text),
+                    ChatCompletionMessageType::Msg => format!("text:{}", message.text),
+                },
+                ChatCompletionEvent::Finish(reason) => format!("finish:{}", reason),
+                ChatCompletionEvent::Error(err) => format!("error:{}", err),
+            })
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "think:想一",
+                "think:想二",
+                "text:答一",
+                "think:想三",
+                "text:答二",
+                "finish:stop"
+            ]
+        );
+    }
+
+    #[test]
+    fn render_handles_empty_single_and_multiple_messages() {
+        let render = |messages: Vec<ChatMessage>| {
+            ChatMessages(messages).render(ReplayReasoning::Drop, DEFAULT_PROMPT_TEMPLATE)
+        };
+        assert!(matches!(render(Vec::new()), Err(EmptyMessages)));
+        // 只有一条消息时直接使用它的内容，不带角色
+        assert_eq!(render(vec![message("user", "  你好 ")]).unwrap(), "你好");
+        assert_eq!(
+            render(vec![message("system", "简短回答"), message("user", "你好")]).unwrap(),
+            "#[system]\n简短回答\n\n#[user]\n你好\n\n"
+        );
+        // 自定义模板中内容里的 {role} 不会被替换
+        let custom = ChatMessages(vec![message("user", "{role}"), message("assistant", "好")])
+            .render(ReplayReasoning::Drop, "<{role}>{content}</{role}>")
+            .unwrap();
+        assert_eq!(custom, "<user>{role}</user><assistant>好</assistant>");
+    }
+
+    #[test]
+    fn take_assistant_prefill_pops_only_a_trailing_assistant_turn() {
+        let mut messages = ChatMessages(vec![
+            message("user", "你好"),
+            message("assistant", "好的，"),
+        ]);
+        assert_eq!(messages.take_assistant_prefill().as_deref(), Some("好的，"));
+        assert_eq!(messages.0.len(), 1);
+        // 末尾不是助手消息、内容为空或只有一条消息时保持不变
+        assert_eq!(messages.take_assistant_prefill(), None);
+        let mut empty = ChatMessages(vec![message("user", "你好"), message("assistant", " ")]);
+        assert_eq!(empty.take_assistant_prefill(), None);
+        assert_eq!(empty.0.len(), 2);
+        let mut single = ChatMessages(vec![message("assistant", "好的，")]);
+        assert_eq!(single.take_assistant_prefill(), None);
+        assert_eq!(single.0.len(), 1);
+    }
+
+    #[test]
+    fn reconnect_resends_the_prompt_until_something_was_emitted() {
+        let prompt = "#[user]\n你好\n\n";
+        assert_eq!(
+            reconnect_prompt(prompt, DEFAULT_PROMPT_TEMPLATE, ""),
+            prompt
+        );
+        assert_eq!(
+            reconnect_prompt(prompt, DEFAULT_PROMPT_TEMPLATE, "你好，"),
+            format!(
+                "#[user]\n你好\n\n#[assistant]\n你好，\n\n#[user]\n{}\n\n",
+                CONTINUATION_PROMPT
+            )
+        );
+    }
+
+    #[test]
+    fn appended_turns_use_the_prompt_template() {
+        let yuanbao = Yuanbao::new(config(
+            "http://127.0.0.1:1",
+            "prompt_template: \"<{role}>{content}</{role}>\\n\"",
+        ));
+        let request = ChatCompletionRequest {
+            json_mode: true,
+            prefill: Some("{\"a\":".to_string()),
+            ..request(vec![message("system", "简短回答"), message("user", "你好")])
+        };
+        let preview = yuanbao.preview_body(&request).unwrap();
+        let prompt = preview["body"]["prompt"].as_str().unwrap();
+        assert_eq!(
+            prompt,
+            format!(
+                "<system>简短回答</system>\n<user>你好</user>\n\n<system>{}</system>\n\n<system>{}</system>\n<assistant>{{\"a\":",
+                JSON_MODE_PROMPT, PREFILL_PROMPT
+            )
+        );
+        assert!(!prompt.contains("#["));
+    }
+}